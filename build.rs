@@ -7,6 +7,9 @@ fn main() -> anyhow::Result<()> {
     let _ = build_data::set_SOURCE_TIMESTAMP();
     let _ = build_data::set_RUSTC_VERSION();
     let _ = build_data::no_debug_rebuilds();
+    // sqlx::migrate! embeds the migrations, a new file needs a rebuild
+    println!("cargo:rerun-if-changed=migrations");
+    println!("cargo:rerun-if-changed=migrations_sqlite");
     Ok(())
 }
 // EOF
@@ -10,7 +10,7 @@ tgt=$HOME/urlharvest/bin
 
 mkdir -p $tgt
 cd target/release
-rsync -var irssi_urlharvest urllog_meta urllog_generator urllog_actions $tgt/
+rsync -var urlharvest irssi_urlharvest urllog_meta urllog_generator urllog_actions $tgt/
 
 exit 0
 # EOF
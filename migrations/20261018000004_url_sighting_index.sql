@@ -0,0 +1,5 @@
+-- The backfill looks up sightings by all of these to skip the ones already imported.
+
+create index url_sighting on url(seen, channel, nick, url);
+
+-- EOF
@@ -0,0 +1,8 @@
+-- Topic changes without URLs update the topic history pages too.
+
+create trigger notify_topic_db_change
+after insert or update or delete on topic
+for each statement
+execute function notify_url_db_changed();
+
+-- EOF
@@ -0,0 +1,7 @@
+-- Keep the message around each URL and what kind of message it was.
+
+alter table url add column msg text not null default '';
+alter table url add column kind text not null default 'privmsg';
+create index url_kind on url(kind);
+
+-- EOF
@@ -0,0 +1,14 @@
+-- Channel topic history, one row per topic change.
+
+create table topic
+(
+    id integer primary key autoincrement,
+    seen bigint not null,
+    channel text not null,
+    nick text not null,
+    topic text not null
+);
+-- re-reading the same logs does not record the changes again
+create unique index topic_change on topic(channel, seen, nick, topic);
+
+-- EOF
@@ -0,0 +1,15 @@
+-- channel_visibility and nick_optout from the config, rewritten by each daemon at startup.
+-- The names are in lowercase, channel '*' is the default visibility.
+
+create table channel_visibility
+(
+    channel text primary key,
+    visibility text not null
+);
+
+create table nick_optout
+(
+    nick text primary key
+);
+
+-- EOF
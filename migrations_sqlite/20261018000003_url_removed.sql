@@ -0,0 +1,17 @@
+-- Sightings removed with remove_url are kept here so that they can be restored.
+-- The metadata is not kept, it gets fetched again after a restore.
+
+create table url_removed
+(
+    id integer primary key,
+    seen bigint not null,
+    channel text not null,
+    nick text not null,
+    url text not null,
+    msg text not null default '',
+    kind text not null default 'privmsg',
+    removed bigint not null
+);
+create index url_removed_url on url_removed(url);
+
+-- EOF
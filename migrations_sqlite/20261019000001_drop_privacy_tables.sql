@@ -0,0 +1,7 @@
+-- The privacy settings are now bound into the queries by each daemon, a shared copy let them overwrite
+-- each other.
+
+drop table channel_visibility;
+drop table nick_optout;
+
+-- EOF
@@ -0,0 +1,101 @@
+// admin_cmd.rs
+
+use crate::*;
+
+// Terminal versions of the search UI and the URL administration actions.
+// search honours channel_visibility and nick_optout like the search UI and stats counts like the public pages,
+// the actions by id see every sighting.
+
+pub async fn search(cfg: &ConfigCommon, query: &SearchQuery) -> anyhow::Result<()> {
+    let db = start_db(cfg).await?;
+    let rows = db.search(query).await?;
+    for row in &rows {
+        println!(
+            "{:>8}  {}  {:>4}x  {}\n          {}\n          {}",
+            row.id,
+            row.seen_last.ts_short_y(),
+            row.seen_cnt,
+            row.channel.sort_dedup_br().replace("<br>", " "),
+            row.url,
+            row.title
+        );
+    }
+    println!("{} URL(s) found", rows.len());
+    Ok(())
+}
+
+pub async fn show(cfg: &ConfigCommon, id: i32) -> anyhow::Result<()> {
+    let db = start_db(cfg).await?;
+    let sightings = db.url_sightings(id).await?;
+    let Some(first) = sightings.first() else {
+        bail!("No URL with id {id}");
+    };
+    println!("{}", first.url);
+    for meta in db.url_meta(id).await? {
+        println!(
+            "  metadata of #{}: [{}] {}\n    {}",
+            meta.url_id, meta.lang, meta.title, meta.descr
+        );
+    }
+    for u in &sightings {
+        println!(
+            "{:>8}  {}  {} <{}> [{}] {}",
+            u.id,
+            u.seen.ts_short_y(),
+            u.channel,
+            u.nick,
+            u.kind,
+            u.msg
+        );
+    }
+    println!("{} sighting(s)", sightings.len());
+    Ok(())
+}
+
+pub async fn remove(cfg: &ConfigCommon, id: i32) -> anyhow::Result<()> {
+    let db = start_db(cfg).await?;
+    let n = db.remove_url(id).await?;
+    println!("Removed {n} sighting(s), `restore {id}` brings them back");
+    Ok(())
+}
+
+pub async fn restore(cfg: &ConfigCommon, id: i32) -> anyhow::Result<()> {
+    let db = start_db(cfg).await?;
+    let n = db.restore_url(id).await?;
+    if n == 0 {
+        bail!("No removed URL with id {id}");
+    }
+    println!("Restored {n} sighting(s), their metadata gets fetched again");
+    Ok(())
+}
+
+// Fetches the metadata of the sighting again right away
+pub async fn refetch(cfg: &ConfigCommon, id: i32) -> anyhow::Result<()> {
+    let db = start_db(cfg).await?;
+    let Some(u) = db.url_sightings(id).await?.into_iter().find(|u| u.id == id) else {
+        bail!("No URL with id {id}");
+    };
+    db.remove_meta(id).await?;
+    meta_cmd::update_meta(&db, &cfg.meta, id, &u.url).await?;
+    for meta in db.url_meta(id).await?.iter().filter(|m| m.url_id == id as i64) {
+        println!("[{}] {}\n{}", meta.lang, meta.title, meta.descr);
+    }
+    Ok(())
+}
+
+pub async fn stats(cfg: &ConfigCommon) -> anyhow::Result<()> {
+    let db = start_db(cfg).await?;
+    let stats = db.stats(i64::MIN, i64::MAX).await?;
+    println!("Sightings:         {}", stats.n_url);
+    if stats.n_url > 0 {
+        println!("First seen:        {}", stats.seen_first.ts_long());
+        println!("Last seen:         {}", stats.seen_last.ts_long());
+    }
+    println!("With metadata:     {}", stats.n_meta);
+    // counted over all the sightings, like the metadata fetcher sees them
+    println!("Pending metadata:  {}", db.pending_meta_count().await?);
+    println!("Topic changes:     {}", stats.n_topic);
+    Ok(())
+}
+
+// EOF
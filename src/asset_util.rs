@@ -0,0 +1,91 @@
+// asset_util.rs
+
+use crate::*;
+
+pub const ARCHIVE_SUBDIR: &str = "archive";
+pub const STATIC_SUBDIR: &str = "static";
+const TEMPLATE_SUBDIR: &str = "templates";
+
+// Compiled in defaults, used whenever template_dir or html_dir do not provide their own.
+
+pub const DEFAULT_TERA_TEMPLATES: &[(&str, &str)] = &[
+    ("url.html.tera", include_str!("../templates/url.html.tera")),
+    ("url2.html.tera", include_str!("../templates/url2.html.tera")),
+];
+
+pub const DEFAULT_ARCHIVE_TEMPLATES: &[(&str, &str)] = &[
+    ("month.html.tera", include_str!("../templates/archive/month.html.tera")),
+    ("index.html.tera", include_str!("../templates/archive/index.html.tera")),
+];
+
+pub const DEFAULT_HBS_TEMPLATES: &[(&str, &str)] = &[
+    (
+        "search_index.html.hbs",
+        include_str!("../templates/search_index.html.hbs"),
+    ),
+    (
+        "search_result_header.html.hbs",
+        include_str!("../templates/search_result_header.html.hbs"),
+    ),
+    (
+        "search_result_row.html.hbs",
+        include_str!("../templates/search_result_row.html.hbs"),
+    ),
+    (
+        "search_result_footer.html.hbs",
+        include_str!("../templates/search_result_footer.html.hbs"),
+    ),
+];
+
+pub const DEFAULT_STATIC_ASSETS: &[(&str, &str)] = &[
+    ("theme.css", include_str!("../static/theme.css")),
+    ("theme.js", include_str!("../static/theme.js")),
+    ("url-actions.js", include_str!("../static/url-actions.js")),
+];
+
+pub fn default_hbs_template(name: &str) -> Option<&'static str> {
+    DEFAULT_HBS_TEMPLATES
+        .iter()
+        .find_map(|(n, content)| (*n == name).then_some(*content))
+}
+
+// Copy the default static assets into html_dir/static unless they are already there
+pub fn install_static_assets(html_dir: &str) -> anyhow::Result<()> {
+    let static_dir = path::Path::new(html_dir).join(STATIC_SUBDIR);
+    fs::create_dir_all(&static_dir)?;
+    for (name, content) in DEFAULT_STATIC_ASSETS {
+        let file = static_dir.join(name);
+        if !file.exists() {
+            info!("Installing default {file:?}");
+            write_atomic(&file, content.as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+// Write out all the defaults for customization, never overwriting existing files
+pub fn write_default_files(dir: &str) -> anyhow::Result<()> {
+    let dir = path::Path::new(dir);
+    let template_dir = dir.join(TEMPLATE_SUBDIR);
+    let sets = [
+        (template_dir.clone(), DEFAULT_TERA_TEMPLATES),
+        (template_dir.join(ARCHIVE_SUBDIR), DEFAULT_ARCHIVE_TEMPLATES),
+        (template_dir, DEFAULT_HBS_TEMPLATES),
+        (dir.join(STATIC_SUBDIR), DEFAULT_STATIC_ASSETS),
+    ];
+    for (set_dir, files) in sets {
+        fs::create_dir_all(&set_dir)?;
+        for (name, content) in files {
+            let file = set_dir.join(name);
+            if file.exists() {
+                warn!("Not overwriting existing {file:?}");
+                continue;
+            }
+            info!("Writing {file:?}");
+            fs::write(&file, content)?;
+        }
+    }
+    Ok(())
+}
+
+// EOF
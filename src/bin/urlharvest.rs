@@ -0,0 +1,128 @@
+// bin/urlharvest.rs
+
+use clap::Subcommand;
+
+use urlharvest::*;
+
+#[derive(Debug, Parser)]
+#[command(version, about = "URL harvester for IRC")]
+struct Cli {
+    #[command(flatten)]
+    opts: OptsBase,
+    #[command(subcommand)]
+    cmd: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Harvest URLs from the irssi logs, like irssi_urlharvest
+    Harvest {
+        /// Read the whole log history before tailing the logs
+        #[arg(short, long)]
+        read_history: bool,
+        /// Remove the stored URL sightings that url_rules deny and exit
+        #[arg(long)]
+        apply_rules: bool,
+        /// With --apply-rules, only list the sightings that would be removed
+        #[arg(long, requires = "apply_rules")]
+        dry_run: bool,
+    },
+    /// Fetch the page metadata of new URLs, like urllog_meta
+    Meta {
+        /// Fetch the metadata of all the URLs still missing it and exit
+        #[arg(short, long)]
+        backlog: bool,
+    },
+    /// Generate the pages whenever the database changes, like urllog_generator
+    Generate,
+    /// Serve the search UI, like urllog_actions
+    Serve,
+    /// Bring the database schema up to date
+    Migrate {
+        /// Also import the URLs and metadata of an original urlharvest SQLite database, like migrate_db
+        #[arg(long, value_name = "DB_URL")]
+        import_legacy: Option<String>,
+    },
+    /// Check the config and the settings of all the daemons and list every problem found
+    CheckConfig,
+    /// Write the built-in templates and static assets into a directory
+    WriteDefaults { dir: String },
+    /// Search the URLs like the search UI, * and ? are wildcards
+    Search {
+        #[arg(long, default_value = "")]
+        chan: String,
+        #[arg(long, default_value = "")]
+        nick: String,
+        #[arg(long, default_value = "")]
+        url: String,
+        #[arg(long, default_value = "")]
+        title: String,
+        #[arg(long, default_value = "")]
+        msg: String,
+        #[arg(long, default_value = "")]
+        kind: String,
+    },
+    /// Show a URL with all its sightings
+    Show { id: i32 },
+    /// Remove all the sightings of a URL
+    Remove { id: i32 },
+    /// Bring back the sightings of a removed URL
+    Restore { id: i32 },
+    /// Fetch the metadata of a sighting again
+    Refetch { id: i32 },
+    /// Show database statistics
+    Stats,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let mut cli = Cli::parse();
+    cli.opts.finalize()?;
+    cli.opts.start_pgm(env!("CARGO_BIN_NAME"));
+    if let Command::WriteDefaults { dir } = &cli.cmd {
+        return write_default_files(&shellexpand::full(dir)?);
+    }
+    let cfg = ConfigCommon::new(&cli.opts)?;
+    debug!("Config:\n{cfg:#?}");
+
+    match cli.cmd {
+        Command::Harvest {
+            read_history,
+            apply_rules,
+            dry_run,
+        } => harvest_cmd::run(&cfg, read_history, apply_rules, dry_run).await,
+        Command::Meta { backlog } => meta_cmd::run(&cfg, backlog).await,
+        Command::Generate => generate_cmd::run(&cfg).await,
+        Command::Serve => serve_cmd::run(&cfg).await,
+        Command::Migrate { import_legacy } => match import_legacy {
+            Some(legacy_db) => migrate_cmd::import_legacy(&cfg, &legacy_db).await,
+            None => migrate_cmd::run(&cfg).await,
+        },
+        Command::CheckConfig => check_cmd::run(&cfg).await,
+        Command::WriteDefaults { .. } => unreachable!("handled before reading the config"),
+        Command::Search {
+            chan,
+            nick,
+            url,
+            title,
+            msg,
+            kind,
+        } => {
+            let query = SearchQuery {
+                chan: chan.sql_search(),
+                nick: nick.sql_search(),
+                url: url.sql_search(),
+                title: title.sql_search(),
+                msg: msg.sql_search(),
+                kind: kind.sql_search(),
+            };
+            admin_cmd::search(&cfg, &query).await
+        }
+        Command::Show { id } => admin_cmd::show(&cfg, id).await,
+        Command::Remove { id } => admin_cmd::remove(&cfg, id).await,
+        Command::Restore { id } => admin_cmd::restore(&cfg, id).await,
+        Command::Refetch { id } => admin_cmd::refetch(&cfg, id).await,
+        Command::Stats => admin_cmd::stats(&cfg).await,
+    }
+}
+// EOF
@@ -0,0 +1,178 @@
+// check_cmd.rs
+
+use crate::*;
+
+// the daemons with their own status_listen address
+const STATUS_DAEMONS: [&str; 3] = [harvest_cmd::DAEMON, meta_cmd::DAEMON, generate_cmd::DAEMON];
+const ALL_DAEMONS: [&str; 4] = [
+    harvest_cmd::DAEMON,
+    meta_cmd::DAEMON,
+    generate_cmd::DAEMON,
+    serve_cmd::DAEMON,
+];
+
+// Checks the settings of all the daemons and prints every problem found
+pub async fn run(cfg: &ConfigCommon) -> anyhow::Result<()> {
+    let problems = check_config(cfg, &ALL_DAEMONS).await;
+    for problem in &problems {
+        println!("{problem}");
+    }
+    if !problems.is_empty() {
+        bail!("{} problem(s) found in the config", problems.len());
+    }
+    println!("Config OK");
+    Ok(())
+}
+
+// Run first thing by each daemon, which then refuses to start with all the problems logged
+pub async fn startup(cfg: &ConfigCommon, daemon: &str) -> anyhow::Result<()> {
+    let problems = check_config(cfg, &[daemon]).await;
+    for problem in &problems {
+        error!("Config problem: {problem}");
+    }
+    if !problems.is_empty() {
+        bail!("{} config problem(s), not starting {daemon}", problems.len());
+    }
+    Ok(())
+}
+
+// Everything wrong with the settings the given daemons use, as "field: problem"
+pub async fn check_config(cfg: &ConfigCommon, daemons: &[&str]) -> Vec<String> {
+    let mut problems = Vec::new();
+    if cfg.db_pool_size == Some(0) {
+        problems.push("db_pool_size: must be at least 1".to_string());
+    }
+    if cfg.db_pool_size.is_some() && DbBackend::from_url(&cfg.db_url) == DbBackend::Sqlite {
+        problems.push("db_pool_size: not used with SQLite, which has a single connection per daemon".to_string());
+    }
+    if let Err(e) = db_check(cfg).await {
+        problems.push(format!("db_url: database not reachable: {e}"));
+    }
+    for name in cfg.status_listen.keys() {
+        if !STATUS_DAEMONS.contains(&name.as_str()) {
+            problems.push(format!(
+                "status_listen: unknown daemon \"{name}\", expected one of {}",
+                STATUS_DAEMONS.join(", ")
+            ));
+        }
+    }
+
+    for &daemon in daemons {
+        if let Some(addr) = cfg.status_listen.get(daemon) {
+            check_listen(&mut problems, &format!("status_listen.{daemon}"), *addr).await;
+        }
+        if daemon == serve_cmd::DAEMON {
+            check_listen(&mut problems, "search_listen", cfg.search_listen).await;
+        }
+        check_settings(cfg, daemon, &mut problems).await;
+    }
+    problems
+}
+
+// The problems with what a SIGHUP reload of the daemon takes into use
+pub async fn check_reload(cfg: &ConfigCommon, daemon: &str) -> Vec<String> {
+    let mut problems = Vec::new();
+    check_settings(cfg, daemon, &mut problems).await;
+    problems
+}
+
+async fn check_settings(cfg: &ConfigCommon, daemon: &str, problems: &mut Vec<String>) {
+    match daemon {
+        harvest_cmd::DAEMON => check_harvest(cfg, problems),
+        generate_cmd::DAEMON => {
+            problems.extend(parse_timezones("template_timezone", &cfg.template_timezone).1);
+            check_dir(problems, "html_dir", &cfg.html_dir, true);
+            if let Err(e) = generate_cmd::check_templates(cfg).await {
+                problems.push(format!("template_dir: {e}"));
+            }
+        }
+        serve_cmd::DAEMON => {
+            if let Err(e) = serve_cmd::check_templates(cfg) {
+                problems.push(format!("template_dir: {e}"));
+            }
+        }
+        _ => {}
+    }
+}
+
+fn check_harvest(cfg: &ConfigCommon, problems: &mut Vec<String>) {
+    check_dir(problems, "irc_log_dir", &cfg.irc_log_dir, false);
+    for (field, re) in [("regex_log", &cfg.regex_log), ("regex_nick", &cfg.regex_nick)] {
+        if let Err(e) = Regex::new(re) {
+            problems.push(format!("{field}: {e}"));
+        }
+    }
+    if let Err(e) = cfg.url_extractor() {
+        problems.push(format!("regex_url: {e}"));
+    }
+    // the rule errors name the rule
+    if let Err(e) = cfg.url_rules() {
+        problems.push(e.to_string());
+    }
+    problems.extend(parse_timezones("log_timezone", &cfg.log_timezone).1);
+
+    // the spool directory is created when needed
+    let spool_dir = path::Path::new(&cfg.spool_file)
+        .ancestors()
+        .skip(1)
+        .find(|dir| dir.as_os_str().is_empty() || dir.exists());
+    match spool_dir {
+        Some(dir) if dir.as_os_str().is_empty() => check_dir(problems, "spool_file", ".", true),
+        Some(dir) => check_dir(problems, "spool_file", &dir.to_string_lossy(), true),
+        None => problems.push(format!("spool_file: no directory in {}", cfg.spool_file)),
+    }
+}
+
+fn check_dir(problems: &mut Vec<String>, field: &str, dir: &str, writable: bool) {
+    if let Err(e) = fs::read_dir(dir) {
+        problems.push(format!("{field}: cannot read directory {dir}: {e}"));
+        return;
+    }
+    if writable {
+        // permission bits do not tell the whole truth, e.g. read-only mounts
+        let probe = path::Path::new(dir).join(format!(".urlharvest-check.{}", std::process::id()));
+        match fs::OpenOptions::new().write(true).create_new(true).open(&probe) {
+            Ok(_) => {
+                let _ = fs::remove_file(&probe);
+            }
+            Err(e) => problems.push(format!("{field}: cannot write to directory {dir}: {e}")),
+        }
+    }
+}
+
+async fn check_listen(problems: &mut Vec<String>, field: &str, addr: net::SocketAddr) {
+    if let Err(e) = tokio::net::TcpListener::bind(addr).await {
+        let hint = match e.kind() {
+            io::ErrorKind::AddrInUse => ", is the daemon already running?",
+            _ => "",
+        };
+        problems.push(format!("{field}: cannot listen to {addr}: {e}{hint}"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn lists_all_the_problems() {
+        let cfg = test_config(serde_json::json!({
+            "log_timezone": {"*": "Mars/Olympus_Mons"},
+            "regex_nick": "^(unclosed",
+            "status_listen": {"urllog_metaa": "127.0.0.1:0"},
+            "spool_file": "/tmp/urlharvest-spool.ndjson"
+        }));
+        let problems = check_config(&cfg, &[harvest_cmd::DAEMON]).await;
+        let fields = problems
+            .iter()
+            .map(|p| p.split(':').next().unwrap_or_default())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            fields,
+            ["status_listen", "irc_log_dir", "regex_nick", "log_timezone"],
+            "{problems:#?}"
+        );
+    }
+}
+
+// EOF
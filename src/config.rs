@@ -2,24 +2,63 @@
 
 use crate::*;
 
-#[derive(Debug, Clone, Parser)]
-pub struct OptsCommon {
-    #[arg(short, long)]
+// Logging and the config file, common to all the binaries and subcommands
+#[derive(Debug, Clone, clap::Args)]
+pub struct OptsBase {
+    #[arg(short, long, global = true)]
     pub verbose: bool,
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     pub debug: bool,
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     pub trace: bool,
 
-    #[arg(short, long, default_value = "$HOME/urlharvest/config/urlharvest.json")]
+    #[arg(
+        short,
+        long,
+        global = true,
+        default_value = "$HOME/urlharvest/config/urlharvest.json"
+    )]
     pub config_file: String,
+}
+
+#[derive(Debug, Clone, Parser)]
+pub struct OptsCommon {
+    #[command(flatten)]
+    pub base: OptsBase,
     #[arg(short, long)]
     pub read_history: bool,
     #[arg(short, long)]
     pub meta_backlog: bool,
+    /// Remove the stored URL sightings that url_rules deny and exit
+    #[arg(long)]
+    pub apply_rules: bool,
+    /// With --apply-rules, only list the sightings that would be removed
+    #[arg(long, requires = "apply_rules")]
+    pub dry_run: bool,
+    /// Write the built-in templates and static assets into this directory and exit
+    #[arg(long)]
+    pub write_defaults: Option<String>,
+}
+
+impl std::ops::Deref for OptsCommon {
+    type Target = OptsBase;
+
+    fn deref(&self) -> &OptsBase {
+        &self.base
+    }
 }
 
 impl OptsCommon {
+    pub fn finalize(&mut self) -> anyhow::Result<()> {
+        self.base.finalize()?;
+        if let Some(dir) = self.write_defaults.as_ref() {
+            self.write_defaults = Some(shellexpand::full(dir)?.into_owned());
+        }
+        Ok(())
+    }
+}
+
+impl OptsBase {
     pub fn finalize(&mut self) -> anyhow::Result<()> {
         self.config_file = shellexpand::full(&self.config_file)?.into_owned();
         Ok(())
@@ -51,6 +90,167 @@ impl OptsCommon {
     }
 }
 
+// environment variables overriding the config fields
+const ENV_PREFIX: &str = "URLHARVEST_";
+// between a section and its field, e.g. URLHARVEST_META__BATCH_SIZE
+const ENV_SECTION_SEP: &str = "__";
+// what SIGHUP does not change, the connections, listeners and files in use are set up on startup.
+// The fields with daemons only matter to those.
+const RESTART_FIELDS: [(&str, &[&str]); 13] = [
+    ("db_url", &[]),
+    ("db_password_file", &[]),
+    ("db_pool_size", &[]),
+    ("db_acquire_timeout", &[]),
+    ("db_statement_timeout", &[]),
+    ("status_listen", &[]),
+    ("metrics_enabled", &[]),
+    ("health_stall_timeout", &[]),
+    ("shutdown_timeout", &[]),
+    ("irc_log_dir", &[harvest_cmd::DAEMON]),
+    ("regex_log", &[harvest_cmd::DAEMON]),
+    ("spool_file", &[harvest_cmd::DAEMON]),
+    ("search_listen", &[serve_cmd::DAEMON]),
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Json,
+    Ndjson,
+    Csv,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExportConfig {
+    pub format: ExportFormat,
+    // relative to html_dir
+    pub file: String,
+    // one row per url instead of one row per channel and url
+    #[serde(default)]
+    pub uniq: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleAction {
+    Allow,
+    Deny,
+}
+
+impl RuleAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RuleAction::Allow => "allow",
+            RuleAction::Deny => "deny",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub enum Visibility {
+    // generated pages, exports and search
+    Public,
+    // search only
+    SearchOnly,
+    // nowhere, the URLs are still stored
+    Hidden,
+}
+
+impl Visibility {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Visibility::Public => "public",
+            Visibility::SearchOnly => "search-only",
+            Visibility::Hidden => "hidden",
+        }
+    }
+}
+
+// One entry of url_rules, everything given has to match
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RuleConfig {
+    pub action: RuleAction,
+    // the domain and its subdomains
+    #[serde(default)]
+    pub domain: Option<String>,
+    // regex on the whole url
+    #[serde(default)]
+    pub regex: Option<String>,
+    #[serde(default)]
+    pub nick: Option<String>,
+    #[serde(default)]
+    pub channel: Option<String>,
+    #[serde(default)]
+    pub network: Option<String>,
+}
+
+// [harvest], irssi_urlharvest
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct HarvestConfig {
+    // seconds between the progress reports while reading the history
+    pub progress_interval: Option<u64>,
+    // seconds a log time may be ahead of the wall clock, e.g. a date guessed wrong around midnight
+    pub clock_slack: Option<u64>,
+    // seconds between attempts to empty the spool
+    pub spool_replay_interval: Option<u64>,
+}
+
+// [meta], urllog_meta
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct MetaConfig {
+    // URLs fetched per database query
+    pub batch_size: Option<usize>,
+    // longer titles are cut
+    pub title_max_len: Option<usize>,
+    // seconds
+    pub connect_timeout: Option<u64>,
+    // seconds for the whole fetch
+    pub request_timeout: Option<u64>,
+}
+
+// [generate], urllog_generator
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct GenerateConfig {
+    // seconds, the pages show the URLs seen within this time
+    pub url_expire: Option<u64>,
+    // seconds to wait for more changes before regenerating, and after a failed generation
+    pub coalesce_delay: Option<u64>,
+}
+
+// [serve], urllog_actions
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ServeConfig {
+    // seconds, sent with the 503 responses when the database is busy
+    pub retry_after: Option<u64>,
+}
+
+// Keeps a secret out of the debug output
+#[derive(Clone)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Secret {
+    fn from(s: String) -> Self {
+        Self(s)
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("\"***\"")
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ConfigCommon {
     pub irc_log_dir: String,
@@ -60,41 +260,463 @@ pub struct ConfigCommon {
     pub html_dir: String,
     pub regex_log: String,
     pub regex_nick: String,
+    // legacy, when set it is used instead of the built-in url extractor
+    #[serde(default)]
     pub regex_url: String,
+    // schemes of the urls to pick up, the :// form only
+    #[serde(default = "default_url_schemes")]
+    pub url_schemes: Vec<String>,
+    // also pick up www.example.com style urls with a known public suffix
+    #[serde(default)]
+    pub url_bare_domains: bool,
     pub search_listen: net::SocketAddr,
     pub tpl_search_index: String,
     pub tpl_search_result_header: String,
     pub tpl_search_result_row: String,
     pub tpl_search_result_footer: String,
+    #[serde(default)]
     pub url_blacklist: Vec<String>,
+    // checked in order, the first matching rule decides and urls no rule matches are kept
+    #[serde(default)]
+    pub url_rules: Vec<RuleConfig>,
+    // per channel, `*` is the default, otherwise public
+    #[serde(default)]
+    pub channel_visibility: HashMap<String, Visibility>,
+    // nicks whose URLs are not shown anywhere
+    #[serde(default)]
+    pub nick_optout: Vec<String>,
+    // for the network rules, defaults to the name of irc_log_dir like irssi's $tag
+    #[serde(default)]
+    pub irc_network: String,
+    // Per-channel timezone of the irssi log times; `*` is the default, otherwise the local zone
+    #[serde(default)]
+    pub log_timezone: HashMap<String, String>,
+    #[serde(default)]
+    pub archive_enabled: bool,
+    #[serde(default)]
+    pub archive_per_channel: bool,
+    #[serde(default)]
+    pub output_gzip: bool,
+    #[serde(default)]
+    pub output_brotli: bool,
+    #[serde(default)]
+    pub output_etag_manifest: bool,
+    #[serde(default)]
+    pub export: Vec<ExportConfig>,
+    // connection pool tuning, see db_util for the defaults
+    #[serde(default)]
+    pub db_pool_size: Option<u32>,
+    // seconds to wait for a free pooled connection
+    #[serde(default)]
+    pub db_acquire_timeout: Option<u64>,
+    // seconds, PostgreSQL only
+    #[serde(default)]
+    pub db_statement_timeout: Option<u64>,
+    // serve /metrics, urllog_actions on search_listen and the others on status_listen
+    #[serde(default)]
+    pub metrics_enabled: bool,
+    // per daemon address for /healthz, /readyz and /metrics, e.g. "urllog_meta": "127.0.0.1:9101"
+    #[serde(default, alias = "metrics_listen")]
+    pub status_listen: HashMap<String, net::SocketAddr>,
+    // seconds without a sign of life from the main loop before /healthz fails and the watchdog starves
+    #[serde(default)]
+    pub health_stall_timeout: Option<u64>,
+    // seconds from SIGTERM or SIGINT until the daemon exits even if it has not finished yet
+    #[serde(default)]
+    pub shutdown_timeout: Option<u64>,
+    // where irssi_urlharvest keeps sightings while the database is unavailable
+    #[serde(default = "default_spool_file")]
+    pub spool_file: String,
+    // file with the database password, which then does not need to be in db_url
+    #[serde(default)]
+    pub db_password_file: Option<String>,
+
+    #[serde(default)]
+    pub harvest: HarvestConfig,
+    #[serde(default)]
+    pub meta: MetaConfig,
+    #[serde(default)]
+    pub generate: GenerateConfig,
+    #[serde(default)]
+    pub serve: ServeConfig,
 
+    // where the config was read from, for reloading
+    #[serde(skip)]
+    pub config_file: String,
+    #[serde(skip)]
+    pub db_password: Option<Secret>,
     #[serde(skip)]
     pub template_tz: Option<HashMap<String, Tz>>,
+    #[serde(skip)]
+    pub log_tz: Option<HashMap<String, Tz>>,
+}
+
+// TOML when the name ends with .toml, JSON otherwise
+fn read_config_file(config_file: &str) -> anyhow::Result<serde_json::Value> {
+    let content = fs::read_to_string(config_file).map_err(|e| anyhow!("Cannot open config file {config_file}: {e}"))?;
+    let value = match path::Path::new(config_file)
+        .extension()
+        .is_some_and(|ext| ext == "toml")
+    {
+        true => toml::from_str(&content).map_err(|e| anyhow!("Cannot parse config file {config_file}: {e}"))?,
+        false => serde_json::from_str(&content).map_err(|e| anyhow!("Cannot parse config file {config_file}: {e}"))?,
+    };
+    Ok(value)
+}
+
+// The config with the required fields empty and everything else at its default, for the field types
+fn default_config_value() -> anyhow::Result<serde_json::Value> {
+    let config: ConfigCommon = serde_json::from_value(serde_json::json!({
+        "irc_log_dir": "",
+        "db_url": "",
+        "template_dir": "",
+        "template_timezone": {},
+        "html_dir": "",
+        "regex_log": "",
+        "regex_nick": "",
+        "search_listen": "0.0.0.0:0",
+        "tpl_search_index": "",
+        "tpl_search_result_header": "",
+        "tpl_search_result_row": "",
+        "tpl_search_result_footer": "",
+    }))?;
+    Ok(serde_json::to_value(&config)?)
+}
+
+// The environment variables as strings. Others than ours may be anything, ours have to be UTF-8.
+fn env_vars(vars: impl Iterator<Item = (ffi::OsString, ffi::OsString)>) -> anyhow::Result<Vec<(String, String)>> {
+    let mut utf8_vars = Vec::new();
+    for (var, val) in vars {
+        match (var.into_string(), val.into_string()) {
+            (Ok(var), Ok(val)) => utf8_vars.push((var, val)),
+            (Ok(var), Err(_)) if var.starts_with(ENV_PREFIX) => bail!("{var} is not valid UTF-8"),
+            (Err(var), _) if var.as_encoded_bytes().starts_with(ENV_PREFIX.as_bytes()) => {
+                bail!("{} is not valid UTF-8", var.to_string_lossy())
+            }
+            _ => {}
+        }
+    }
+    Ok(utf8_vars)
+}
+
+// Whether the field at the pointer takes a string. A field without a default value gets the value as a string
+// if the config still parses with it. Fields unknown to us are strings, load reports them.
+fn is_string_field(defaults: &serde_json::Value, pointer: &str, val: &str) -> bool {
+    match defaults.pointer(pointer) {
+        Some(serde_json::Value::Null) => {
+            let mut probe = defaults.clone();
+            if let Some(field) = probe.pointer_mut(pointer) {
+                *field = serde_json::Value::String(val.to_string());
+            }
+            serde_json::from_value::<ConfigCommon>(probe).is_ok()
+        }
+        Some(default) => default.is_string(),
+        None => true,
+    }
+}
+
+// URLHARVEST_DB_URL sets db_url and URLHARVEST_META__BATCH_SIZE sets batch_size in the meta section.
+// String fields take the value as is, the others as JSON when it parses as such, e.g. 8, true or
+// {"*": "UTC"}, and otherwise as a string. Returns the variables with their JSON pointers.
+fn env_overrides(
+    value: &mut serde_json::Value,
+    defaults: &serde_json::Value,
+    vars: impl Iterator<Item = (String, String)>,
+) -> anyhow::Result<Vec<(String, String)>> {
+    let mut overrides = Vec::new();
+    for (var, val) in vars {
+        let Some(name) = var.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        let path = name
+            .to_lowercase()
+            .split(ENV_SECTION_SEP)
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        let (field, sections) = path
+            .split_last()
+            .ok_or_else(|| anyhow!("{var} names no config field"))?;
+        let mut table = value
+            .as_object_mut()
+            .ok_or_else(|| anyhow!("The config is not a table of fields"))?;
+        for section in sections {
+            table = table
+                .entry(section.as_str())
+                .or_insert_with(|| serde_json::Value::Object(Default::default()))
+                .as_object_mut()
+                .ok_or_else(|| anyhow!("{var}: {section} is not a section"))?;
+        }
+        let pointer = format!("/{}", path.join("/"));
+        let new_val = match serde_json::from_str(&val) {
+            Ok(parsed) if !is_string_field(defaults, &pointer, &val) => parsed,
+            _ => serde_json::Value::String(val),
+        };
+        table.insert(field.to_string(), new_val);
+        overrides.push((var, pointer));
+    }
+    Ok(overrides)
+}
+
+// The valid zones, and a problem for each invalid one
+pub fn parse_timezones(field: &str, zones: &HashMap<String, String>) -> (HashMap<String, Tz>, Vec<String>) {
+    let mut parsed = HashMap::new();
+    let mut problems = Vec::new();
+    for (k, v) in zones {
+        match v.parse::<Tz>() {
+            Ok(tz) => {
+                parsed.insert(k.to_string(), tz);
+            }
+            Err(e) => problems.push(format!("{field}: \"{k}\": \"{v}\" - {e}")),
+        }
+    }
+    (parsed, problems)
+}
+
+fn default_spool_file() -> String {
+    DEFAULT_SPOOL_FILE.to_string()
+}
+
+fn default_url_schemes() -> Vec<String> {
+    DEFAULT_URL_SCHEMES.iter().map(|s| s.to_string()).collect()
 }
 
 impl ConfigCommon {
-    pub fn new(opts: &OptsCommon) -> anyhow::Result<Self> {
-        debug!("Reading config file {}", &opts.config_file);
-        let mut config: ConfigCommon = serde_json::from_reader(io::BufReader::new(fs::File::open(&opts.config_file)?))?;
+    // Only an unreadable or unparseable file fails here, check_cmd reports everything else
+    pub fn new(opts: &OptsBase) -> anyhow::Result<Self> {
+        Self::load(&opts.config_file)
+    }
+
+    // Reads the config file again, with the current environment
+    pub fn reload(&self) -> anyhow::Result<Self> {
+        Self::load(&self.config_file)
+    }
+
+    fn load(config_file: &str) -> anyhow::Result<Self> {
+        debug!("Reading config file {config_file}");
+        let mut value = read_config_file(config_file)?;
+        let overrides = env_overrides(
+            &mut value,
+            &default_config_value()?,
+            env_vars(env::vars_os())?.into_iter(),
+        )?;
+        let mut config: ConfigCommon = serde_json::from_value(value).map_err(|e| match overrides.is_empty() {
+            true => anyhow!("Bad config in {config_file}: {e}"),
+            false => {
+                let vars = overrides.iter().map(|(var, _)| var.as_str()).collect::<Vec<_>>();
+                anyhow!("Bad config in {config_file} with {}: {e}", vars.join(", "))
+            }
+        })?;
+        // a misspelled variable would otherwise be silently ignored
+        let known = serde_json::to_value(&config)?;
+        for (var, pointer) in &overrides {
+            if known.pointer(pointer).is_none() {
+                bail!("{var} does not match any config field");
+            }
+            debug!("Config field {pointer} set by {var}");
+        }
+
+        config.config_file = config_file.to_string();
         config.irc_log_dir = shellexpand::full(&config.irc_log_dir)?.into_owned();
         config.template_dir = shellexpand::full(&config.template_dir)?.into_owned();
         config.html_dir = shellexpand::full(&config.html_dir)?.into_owned();
-
-        let mut template_tz = HashMap::new();
-        // Parse the timezone strings
-        for (k, v) in &config.template_timezone {
-            match v.as_str().parse::<Tz>() {
-                Ok(tz) => {
-                    template_tz.insert(k.to_string(), tz);
-                }
-                Err(e) => {
-                    bail!("error parsing url_dup_timezone \"{k}\": \"{v}\" - {e}");
-                }
-            }
+        config.spool_file = shellexpand::full(&config.spool_file)?.into_owned();
+        if let Some(file) = config.db_password_file.as_ref() {
+            let file = shellexpand::full(file)?.into_owned();
+            let password =
+                fs::read_to_string(&file).map_err(|e| anyhow!("Cannot read db_password_file {file}: {e}"))?;
+            config.db_password = Some(password.trim_end_matches(['\r', '\n']).to_string().into());
+            config.db_password_file = Some(file);
+            config.db_connect_url()?;
         }
-        config.template_tz = Some(template_tz);
+
+        // invalid zones are left out, the daemons refuse to start with them
+        config.template_tz = Some(parse_timezones("template_timezone", &config.template_timezone).0);
+        config.log_tz = Some(parse_timezones("log_timezone", &config.log_timezone).0);
 
         Ok(config)
     }
+
+    // The fields that differ in new and only take effect when the daemon is restarted
+    pub fn restart_needed(&self, new: &ConfigCommon, daemon: &str) -> Vec<&'static str> {
+        let (Ok(old), Ok(new)) = (serde_json::to_value(self), serde_json::to_value(new)) else {
+            return Vec::new();
+        };
+        RESTART_FIELDS
+            .into_iter()
+            .filter(|(field, daemons)| {
+                (daemons.is_empty() || daemons.contains(&daemon)) && old.get(field) != new.get(field)
+            })
+            .map(|(field, _)| field)
+            .collect()
+    }
+
+    // db_url with the password from db_password_file, for connecting only
+    pub fn db_connect_url(&self) -> anyhow::Result<String> {
+        let Some(password) = self.db_password.as_ref() else {
+            return Ok(self.db_url.clone());
+        };
+        let mut url = url::Url::parse(&self.db_url)?;
+        if url.set_password(Some(password.expose())).is_err() {
+            bail!("db_url needs a host for the password from db_password_file");
+        }
+        Ok(url.into())
+    }
+
+    pub fn log_tz(&self, chan: &str) -> LogTz {
+        match self.log_tz.as_ref().and_then(|map| get_wild(map, chan)) {
+            Some(tz) => LogTz::Zone(*tz),
+            None => LogTz::Local,
+        }
+    }
+
+    pub fn privacy(&self) -> Privacy {
+        Privacy {
+            channels: self
+                .channel_visibility
+                .iter()
+                .map(|(chan, v)| (chan.to_lowercase(), *v))
+                .collect(),
+            nick_optout: self.nick_optout.iter().map(|n| n.to_lowercase()).collect(),
+        }
+    }
+
+    pub fn url_rules(&self) -> anyhow::Result<RuleSet> {
+        let network = match self.irc_network.is_empty() {
+            false => self.irc_network.clone(),
+            true => path::Path::new(&self.irc_log_dir)
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+        };
+        RuleSet::new(&self.url_rules, &self.url_blacklist, &network)
+    }
+
+    pub fn url_extractor(&self) -> anyhow::Result<UrlExtractor> {
+        if self.regex_url.is_empty() {
+            UrlExtractor::new(&self.url_schemes, self.url_bare_domains)
+        } else {
+            UrlExtractor::with_regex(&self.regex_url)
+        }
+    }
+}
+
+// The required fields with placeholder values, and the given fields on top, for the tests
+#[cfg(test)]
+pub fn test_config(fields: serde_json::Value) -> ConfigCommon {
+    let mut value = serde_json::json!({
+        "irc_log_dir": "/nonexistent/irclogs",
+        "db_url": "sqlite::memory:",
+        "template_dir": "/nonexistent/templates",
+        "template_timezone": {},
+        "html_dir": "/nonexistent/html",
+        "regex_log": "^(#\\S*)\\.log$",
+        "regex_nick": "^(\\S+)",
+        "search_listen": "127.0.0.1:0",
+        "tpl_search_index": "search_index.html.hbs",
+        "tpl_search_result_header": "search_result_header.html.hbs",
+        "tpl_search_result_row": "search_result_row.html.hbs",
+        "tpl_search_result_footer": "search_result_footer.html.hbs"
+    });
+    if let (Some(value), serde_json::Value::Object(fields)) = (value.as_object_mut(), fields) {
+        value.extend(fields);
+    }
+    serde_json::from_value(value).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example(name: &str) -> serde_json::Value {
+        let file = format!("{}/config/{name}", env!("CARGO_MANIFEST_DIR"));
+        let config: ConfigCommon = serde_json::from_value(read_config_file(&file).unwrap()).unwrap();
+        serde_json::to_value(config).unwrap()
+    }
+
+    #[test]
+    fn toml_and_json_examples_agree() {
+        assert_eq!(example("urlharvest.toml"), example("urlharvest.json"));
+    }
+
+    #[test]
+    fn restart_needed_for_connections_only() {
+        let file = format!("{}/config/urlharvest.json", env!("CARGO_MANIFEST_DIR"));
+        let cfg: ConfigCommon = serde_json::from_value(read_config_file(&file).unwrap()).unwrap();
+        let mut new = cfg.clone();
+        new.url_blacklist.push("https://example.com".to_string());
+        new.meta.batch_size = Some(20);
+        new.search_listen = "127.0.0.1:8081".parse().unwrap();
+        assert!(cfg.restart_needed(&new, generate_cmd::DAEMON).is_empty());
+        assert_eq!(cfg.restart_needed(&new, serve_cmd::DAEMON), ["search_listen"]);
+        new.db_url = "sqlite:/tmp/url.db".to_string();
+        assert_eq!(cfg.restart_needed(&new, generate_cmd::DAEMON), ["db_url"]);
+    }
+
+    #[test]
+    fn env_overrides_fields_and_sections() {
+        let mut value = serde_json::json!({"db_url": "postgres:///url"});
+        let vars = [
+            ("URLHARVEST_DB_URL", "sqlite:/tmp/url.db"),
+            ("URLHARVEST_IRC_NETWORK", "42"),
+            ("URLHARVEST_DB_PASSWORD_FILE", "7"),
+            ("URLHARVEST_DB_POOL_SIZE", "8"),
+            ("URLHARVEST_META__BATCH_SIZE", "20"),
+            ("URLHARVEST_LOG_TIMEZONE", r#"{"*": "UTC"}"#),
+            ("URLHARVEST_NICK_OPTOUT", r#"["1234"]"#),
+            ("URLHARVEST_UNKNOWN_FIELD", "5"),
+            ("HOME", "/root"),
+        ];
+        let defaults = default_config_value().unwrap();
+        let overrides = env_overrides(
+            &mut value,
+            &defaults,
+            vars.iter().map(|(k, v)| (k.to_string(), v.to_string())),
+        )
+        .unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "db_url": "sqlite:/tmp/url.db",
+                "irc_network": "42",
+                "db_password_file": "7",
+                "db_pool_size": 8,
+                "meta": {"batch_size": 20},
+                "log_timezone": {"*": "UTC"},
+                "nick_optout": ["1234"],
+                "unknown_field": "5",
+            })
+        );
+        assert_eq!(
+            overrides[4],
+            (
+                "URLHARVEST_META__BATCH_SIZE".to_string(),
+                "/meta/batch_size".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn env_vars_need_utf8_for_ours_only() {
+        use std::os::unix::ffi::OsStringExt;
+
+        let bad = || ffi::OsString::from_vec(b"\xff".to_vec());
+        let ours = |name: &str| ffi::OsString::from(format!("URLHARVEST_{name}"));
+        let vars = env_vars(
+            [
+                (ours("DB_URL"), "sqlite:/tmp/url.db".into()),
+                (bad(), bad()),
+                ("LANG".into(), bad()),
+            ]
+            .into_iter(),
+        );
+        assert_eq!(
+            vars.unwrap(),
+            [("URLHARVEST_DB_URL".to_string(), "sqlite:/tmp/url.db".to_string())]
+        );
+        assert!(env_vars([(ours("DB_URL"), bad())].into_iter()).is_err());
+        let mut var = ours("").into_vec();
+        var.push(0xff);
+        assert!(env_vars([(ffi::OsString::from_vec(var), "x".into())].into_iter()).is_err());
+    }
 }
 // EOF
@@ -1,23 +1,45 @@
 // db_util.rs
 
-use sqlx::{Pool, Postgres};
+use sqlx::{
+    AnyConnection, AnyPool, AssertSqlSafe, Connection, Executor,
+    any::{AnyPoolOptions, install_default_drivers},
+    postgres::{PgConnection, PgListener},
+};
+use tokio::sync::watch;
 
 use crate::*;
 
 const RETRY_CNT: usize = 5;
 const RETRY_SLEEP: u64 = 1;
 pub const DB_CHANGE_CHANNEL: &str = "url_db_changed";
+// private channel for the round trips that check the listener connection
+const DB_PROBE_CHANNEL: &str = "url_db_probe";
 
-#[derive(Debug, sqlx::FromRow)]
+const DB_POOL_SZ: u32 = 5;
+const DB_ACQUIRE_TIMEOUT: u64 = 5;
+
+const SQLITE_SCHEME: &str = "sqlite:";
+// SQLite allows a single writer only, and our manual transactions need to stay on one connection.
+// The long history import writes through a connection of its own, so the pool stays free meanwhile.
+const SQLITE_POOL_SZ: u32 = 1;
+const SQLITE_PRAGMAS: &str = "pragma journal_mode = wal; pragma busy_timeout = 10000; pragma foreign_keys = on";
+const SQLITE_POLL: u64 = 2;
+const SLEEP_POLL_CLOSED: u64 = 1;
+
+#[derive(Debug, Clone, sqlx::FromRow)]
 pub struct DbUrl {
     pub id: i32,
     pub seen: i64,
     pub channel: String,
     pub nick: String,
     pub url: String,
+    #[sqlx(default)]
+    pub msg: String,
+    #[sqlx(default)]
+    pub kind: String,
 }
 
-#[derive(Debug, sqlx::FromRow)]
+#[derive(Debug, Clone, sqlx::FromRow)]
 pub struct DbMeta {
     pub id: i32,
     pub url_id: i64,
@@ -26,20 +48,263 @@ pub struct DbMeta {
     pub descr: String,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DbBackend {
+    Postgres,
+    Sqlite,
+}
+
+impl DbBackend {
+    pub fn from_url(db_url: &str) -> Self {
+        if db_url.starts_with(SQLITE_SCHEME) {
+            DbBackend::Sqlite
+        } else {
+            DbBackend::Postgres
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct DbCtx {
-    pub dbc: Pool<Postgres>,
+    pub dbc: AnyPool,
+    pub backend: DbBackend,
+    // with the password from db_password_file
+    pub db_url: Secret,
+    // bumped on every change made through this context, for listeners in the same process
+    pub changed: watch::Sender<u64>,
+    // the settings of this process, bound into the queries
+    privacy: std::sync::RwLock<Privacy>,
 }
 
-#[derive(Debug)]
+impl DbCtx {
+    fn privacy_params(&self, level: Visibility) -> (String, String) {
+        match self.privacy.read() {
+            Ok(p) => p.sql_params(level),
+            Err(e) => e.into_inner().sql_params(level),
+        }
+    }
+
+    pub fn notify_changed(&self) {
+        self.changed.send_modify(|v| *v = v.wrapping_add(1));
+    }
+
+    pub async fn listener(&self) -> Result<DbListener, sqlx::Error> {
+        match self.backend {
+            DbBackend::Postgres => {
+                let mut listener = PgListener::connect(self.db_url.expose()).await?;
+                listener.listen_all([DB_CHANGE_CHANNEL, DB_PROBE_CHANNEL]).await?;
+                Ok(DbListener::Postgres {
+                    listener,
+                    pending: None,
+                })
+            }
+            DbBackend::Sqlite => {
+                // data_version only reflects commits by other connections, hence a dedicated one
+                let mut conn = AnyConnection::connect(self.db_url.expose()).await?;
+                let data_version = sqlite_data_version(&mut conn).await?;
+                Ok(DbListener::Sqlite {
+                    changed: self.changed.subscribe(),
+                    conn,
+                    data_version,
+                })
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DbChange {
+    Notified,
+    // Notifications may have been lost, callers should reconcile against the current state
+    Reconnected,
+}
+
+// Waits for database changes. PostgreSQL has LISTEN/NOTIFY, for SQLite we get woken up
+// by changes made in this process and poll data_version for changes made by other processes.
+pub enum DbListener {
+    Postgres {
+        listener: PgListener,
+        // a change that arrived while waiting for a probe
+        pending: Option<DbChange>,
+    },
+    Sqlite {
+        changed: watch::Receiver<u64>,
+        conn: AnyConnection,
+        data_version: i64,
+    },
+}
+
+impl DbListener {
+    pub async fn recv(&mut self) -> Result<DbChange, sqlx::Error> {
+        match self {
+            DbListener::Postgres { listener, pending } => {
+                if let Some(change) = pending.take() {
+                    return Ok(change);
+                }
+                loop {
+                    match listener.try_recv().await? {
+                        // the answer to a probe that timed out
+                        Some(notification) if notification.channel() == DB_PROBE_CHANNEL => {}
+                        Some(notification) => {
+                            trace!(
+                                "Database update notification from backend {}",
+                                notification.process_id()
+                            );
+                            return Ok(DbChange::Notified);
+                        }
+                        None => return Ok(DbChange::Reconnected),
+                    }
+                }
+            }
+            DbListener::Sqlite {
+                changed,
+                conn,
+                data_version,
+            } => loop {
+                tokio::select! {
+                    res = changed.changed() => {
+                        if res.is_ok() {
+                            trace!("Database change in this process");
+                            return Ok(DbChange::Notified);
+                        }
+                        // all senders are gone, keep polling
+                        sleep(Duration::new(SLEEP_POLL_CLOSED, 0)).await;
+                    }
+                    _ = sleep(Duration::new(SQLITE_POLL, 0)) => {}
+                }
+                let version = sqlite_data_version(conn).await?;
+                if version != *data_version {
+                    trace!("Database data_version changed {data_version} -> {version}");
+                    *data_version = version;
+                    return Ok(DbChange::Notified);
+                }
+            },
+        }
+    }
+
+    // Skip over notifications that are already waiting
+    pub fn drain(&mut self) {
+        match self {
+            DbListener::Postgres { listener, pending } => {
+                *pending = None;
+                while listener.next_buffered().is_some() {}
+            }
+            DbListener::Sqlite { changed, .. } => {
+                changed.borrow_and_update();
+            }
+        }
+    }
+
+    // Checks the listener's own connection, for PostgreSQL with a notification round trip through it
+    pub async fn probe(&mut self, db: &DbCtx) -> Result<(), sqlx::Error> {
+        match self {
+            DbListener::Postgres { listener, pending } => {
+                let token = format!("{}-{}", std::process::id(), Utc::now().timestamp_micros());
+                let sql = format!("notify {DB_PROBE_CHANNEL}, '{token}'");
+                db.dbc.execute(AssertSqlSafe(sql)).await?;
+                loop {
+                    match listener.try_recv().await? {
+                        Some(notification) if notification.channel() == DB_PROBE_CHANNEL => {
+                            if notification.payload() == token {
+                                return Ok(());
+                            }
+                        }
+                        Some(_) => {
+                            pending.get_or_insert(DbChange::Notified);
+                        }
+                        // a fresh connection, our notification went to the old one
+                        None => {
+                            *pending = Some(DbChange::Reconnected);
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+            DbListener::Sqlite { conn, .. } => sqlite_data_version(conn).await.map(|_| ()),
+        }
+    }
+}
+
+async fn sqlite_data_version(conn: &mut AnyConnection) -> Result<i64, sqlx::Error> {
+    let (version,): (i64,) = sqlx::query_as("pragma data_version").fetch_one(conn).await?;
+    Ok(version)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UrlCtx {
     pub ts: i64,
     pub chan: String,
     pub nick: String,
     pub url: String,
+    // the message the url was seen in, capped at MSG_MAX_LEN
+    #[serde(default)]
+    pub msg: String,
+    #[serde(default)]
+    pub kind: MsgKind,
 }
 
-#[derive(Debug)]
+// channel_visibility and nick_optout from the config, the names in lowercase
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Privacy {
+    pub channels: std::collections::BTreeMap<String, Visibility>,
+    pub nick_optout: std::collections::BTreeSet<String>,
+}
+
+impl Privacy {
+    pub fn visibility(&self, chan: &str) -> Visibility {
+        self.channels
+            .get(&chan.to_lowercase())
+            .or_else(|| self.channels.get("*"))
+            .copied()
+            .unwrap_or(Visibility::Public)
+    }
+
+    // Whether a sighting may be shown where `level` is allowed, e.g. SearchOnly for the search
+    pub fn shows(&self, chan: &str, nick: &str, level: Visibility) -> bool {
+        !self.nick_optout.contains(&nick.to_lowercase()) && self.visibility(chan) <= level
+    }
+
+    // Changes whenever the settings do
+    pub fn fingerprint(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    // The two parameters of sql_visible! for the given level: the opted-out nicks as " a b " and the channels
+    // as "+ #a #b " when only the listed ones are shown or "- #a #b " when the listed ones are hidden
+    pub fn sql_params(&self, level: Visibility) -> (String, String) {
+        let nicks = self.nick_optout.iter().fold(" ".to_owned(), |acc, n| acc + n + " ");
+        let default_shown = self.visibility("*") <= level;
+        let mut chans = if default_shown { "-" } else { "+" }.to_owned();
+        for (chan, visibility) in &self.channels {
+            if chan != "*" && (*visibility <= level) != default_shown {
+                chans.push(' ');
+                chans.push_str(chan);
+            }
+        }
+        chans.push(' ');
+        (nicks, chans)
+    }
+}
+
+// A topic change, chan is the channel of the log
+#[derive(Debug, Clone)]
+pub struct TopicCtx {
+    pub ts: i64,
+    pub chan: String,
+    pub nick: String,
+    pub topic: String,
+}
+
+#[derive(Debug, Clone, Default, sqlx::FromRow)]
+pub struct DbTopic {
+    pub id: i32,
+    pub seen: i64,
+    pub channel: String,
+    pub nick: String,
+    pub topic: String,
+}
+
+#[derive(Debug, Clone)]
 pub struct MetaCtx {
     pub url_id: i32,
     pub lang: String,
@@ -47,57 +312,1551 @@ pub struct MetaCtx {
     pub descr: String,
 }
 
+pub async fn db_ping(dbc: &AnyPool) -> Result<(), sqlx::Error> {
+    sqlx::query(SQL_PING).execute(dbc).await?;
+    Ok(())
+}
+
+// Connects once without touching the schema, for the config check
+pub async fn db_check(c: &ConfigCommon) -> Result<(), sqlx::Error> {
+    install_default_drivers();
+    let db_url = db_connect_url(c)?;
+    let db_url = match DbBackend::from_url(&db_url) {
+        DbBackend::Postgres => db_url,
+        DbBackend::Sqlite => sqlite_url(&db_url),
+    };
+    let dbc = AnyPoolOptions::new()
+        .max_connections(1)
+        .acquire_timeout(Duration::new(c.db_acquire_timeout.unwrap_or(DB_ACQUIRE_TIMEOUT), 0))
+        .connect(&db_url)
+        .await?;
+    let res = db_ping(&dbc).await;
+    dbc.close().await;
+    res
+}
+
 pub async fn start_db(c: &ConfigCommon) -> Result<DbCtx, sqlx::Error> {
-    let dbc = sqlx::PgPool::connect(&c.db_url).await?;
-    sqlx::migrate!().run(&dbc).await?; // will create tables if necessary
-    let db = DbCtx { dbc };
+    install_default_drivers();
+    let db_url = db_connect_url(c)?;
+    let backend = DbBackend::from_url(&db_url);
+    let dbc = match backend {
+        DbBackend::Postgres => {
+            let statement_timeout = c.db_statement_timeout;
+            let dbc = AnyPoolOptions::new()
+                .max_connections(c.db_pool_size.unwrap_or(DB_POOL_SZ))
+                .acquire_timeout(Duration::new(c.db_acquire_timeout.unwrap_or(DB_ACQUIRE_TIMEOUT), 0))
+                .after_connect(move |conn, _meta| {
+                    Box::pin(async move {
+                        if let Some(secs) = statement_timeout {
+                            let sql = format!("set statement_timeout = {}", secs * 1000);
+                            conn.execute(AssertSqlSafe(sql)).await?;
+                        }
+                        Ok(())
+                    })
+                })
+                .connect(&db_url)
+                .await?;
+            sqlx::migrate!("./migrations").run(&dbc).await?; // will create tables if necessary
+            dbc
+        }
+        DbBackend::Sqlite => {
+            let dbc = AnyPoolOptions::new()
+                .max_connections(SQLITE_POOL_SZ)
+                .acquire_timeout(Duration::new(c.db_acquire_timeout.unwrap_or(DB_ACQUIRE_TIMEOUT), 0))
+                .after_connect(|conn, _meta| Box::pin(async move { conn.execute(SQLITE_PRAGMAS).await.map(|_| ()) }))
+                .connect(&sqlite_url(&db_url))
+                .await?;
+            sqlx::migrate!("./migrations_sqlite").run(&dbc).await?;
+            dbc
+        }
+    };
+    let (changed, _) = watch::channel(0);
+    let db = DbCtx {
+        dbc,
+        backend,
+        db_url: db_url.into(),
+        changed,
+        privacy: std::sync::RwLock::new(c.privacy()),
+    };
     Ok(db)
 }
 
-const SQL_INSERT_URL: &str = "insert into url (seen, channel, nick, url) \
+fn db_connect_url(c: &ConfigCommon) -> Result<String, sqlx::Error> {
+    c.db_connect_url().map_err(|e| sqlx::Error::Configuration(e.into()))
+}
+
+// Create the database file if it does not exist yet
+fn sqlite_url(db_url: &str) -> String {
+    if db_url.contains("mode=") {
+        db_url.to_string()
+    } else if db_url.contains('?') {
+        format!("{db_url}&mode=rwc")
+    } else {
+        format!("{db_url}?mode=rwc")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UrlGrouping {
+    // one row per channel and URL
+    PerChannel,
+    // one row per URL, channels joined with spaces
+    Uniq,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+// URL sightings aggregated over the grouping, nicks are joined with spaces.
+// msg and kind are from the first sighting.
+#[derive(Debug, Clone, Default, sqlx::FromRow)]
+pub struct DbUrlRow {
+    pub id: i32,
+    pub seen_first: i64,
+    pub seen_last: i64,
+    pub seen_cnt: i64,
+    pub channel: String,
+    pub nick: String,
+    pub url: String,
+    pub title: String,
+    pub msg: String,
+    pub kind: String,
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct DbNoMeta {
+    pub id: i32,
+    pub url: String,
+    pub seen: i64,
+}
+
+// Summary of the sightings shown on the pages in a time range, used to detect changes cheaply
+#[derive(Debug, Clone, Default, PartialEq, Eq, sqlx::FromRow)]
+pub struct DbStats {
+    pub seen_first: i64,
+    pub seen_last: i64,
+    pub n_url: i64,
+    pub max_id: i32,
+    pub n_meta: i64,
+    pub max_meta_id: i32,
+    pub n_topic: i64,
+}
+
+// SQL LIKE patterns, see StringSqlSearch
+#[derive(Debug, Clone, Default)]
+pub struct SearchQuery {
+    pub chan: String,
+    pub nick: String,
+    pub url: String,
+    pub title: String,
+    pub msg: String,
+    pub kind: String,
+}
+
+// Typed access to the URL database, so that binaries do not need to embed SQL.
+// DbCtx is the real thing and MemRepo stands in for it in unit tests.
+pub trait UrlRepo: Send + Sync {
+    // Replaces the privacy settings that all the reads below honour
+    fn set_privacy(&self, privacy: &Privacy) -> impl Future<Output = Result<(), sqlx::Error>> + Send;
+    // Adds all the sightings in one transaction, returns the number of rows inserted
+    fn add_sightings(&self, urls: &[UrlCtx]) -> impl Future<Output = Result<u64, sqlx::Error>> + Send;
+    fn add_meta(&self, meta: &MetaCtx) -> impl Future<Output = Result<u64, sqlx::Error>> + Send;
+    // Records topic changes, the ones already recorded are skipped
+    fn add_topics(&self, topics: &[TopicCtx]) -> impl Future<Output = Result<u64, sqlx::Error>> + Send;
+    // Topic history of the channel in [ts_start, ts_end), oldest first
+    fn topics(
+        &self,
+        chan: &str,
+        ts_start: i64,
+        ts_end: i64,
+    ) -> impl Future<Output = Result<Vec<DbTopic>, sqlx::Error>> + Send;
+    // Sightings without metadata yet, ordered by time
+    fn pending_meta(
+        &self,
+        order: SortOrder,
+        limit: usize,
+    ) -> impl Future<Output = Result<Vec<DbNoMeta>, sqlx::Error>> + Send;
+    fn pending_meta_count(&self) -> impl Future<Output = Result<i64, sqlx::Error>> + Send;
+    // URLs matching all the LIKE patterns, most recent first
+    fn search(&self, q: &SearchQuery) -> impl Future<Output = Result<Vec<DbUrlRow>, sqlx::Error>> + Send;
+    // URLs seen after ts_limit, most recent first
+    fn recent(
+        &self,
+        ts_limit: i64,
+        grouping: UrlGrouping,
+    ) -> impl Future<Output = Result<Vec<DbUrlRow>, sqlx::Error>> + Send;
+    // URLs seen in [ts_start, ts_end), oldest first
+    fn range(
+        &self,
+        ts_start: i64,
+        ts_end: i64,
+        grouping: UrlGrouping,
+    ) -> impl Future<Output = Result<Vec<DbUrlRow>, sqlx::Error>> + Send;
+    // Removes all sightings of the URL with the given id, they are kept aside for restore_url
+    fn remove_url(&self, id: i32) -> impl Future<Output = Result<u64, sqlx::Error>> + Send;
+    // Brings back the removed sightings of the URL with the given id, without metadata
+    fn restore_url(&self, id: i32) -> impl Future<Output = Result<u64, sqlx::Error>> + Send;
+    // All sightings of the URL with the given id, oldest first
+    fn url_sightings(&self, id: i32) -> impl Future<Output = Result<Vec<DbUrl>, sqlx::Error>> + Send;
+    // Metadata of the sightings of the URL with the given id
+    fn url_meta(&self, id: i32) -> impl Future<Output = Result<Vec<DbMeta>, sqlx::Error>> + Send;
+    // Removes the metadata so that it gets fetched again
+    fn remove_meta(&self, url_id: i32) -> impl Future<Output = Result<u64, sqlx::Error>> + Send;
+    // Single sightings with id > after_id, in id order
+    fn sightings(&self, after_id: i32, limit: usize) -> impl Future<Output = Result<Vec<DbUrl>, sqlx::Error>> + Send;
+    // Removes single sightings restorably, unlike remove_url the other sightings of the same URL stay
+    fn remove_sightings(&self, ids: &[i32]) -> impl Future<Output = Result<u64, sqlx::Error>> + Send;
+    fn stats(&self, ts_start: i64, ts_end: i64) -> impl Future<Output = Result<DbStats, sqlx::Error>> + Send;
+    // Copies rows from another database keeping their ids
+    fn import_urls(&self, urls: &[DbUrl]) -> impl Future<Output = Result<u64, sqlx::Error>> + Send;
+    fn import_meta(&self, meta: &[MetaCtx]) -> impl Future<Output = Result<u64, sqlx::Error>> + Send;
+}
+
+// Rows of the table that the privacy settings of this process allow, with the nicks and channels parameters
+// from Privacy::sql_params. The names are matched with spaces around them so that one is never a part of
+// another. Filtering before grouping keeps hidden nicks and channels out of the aggregates too.
+macro_rules! sql_visible {
+    ($table:literal, $nicks:literal, $chans:literal) => {
+        concat!(
+            "length(replace(",
+            $nicks,
+            ", ' ' || lower(",
+            $table,
+            ".nick) || ' ', '')) = length(",
+            $nicks,
+            ") and (",
+            $chans,
+            " like '+%') = (length(replace(",
+            $chans,
+            ", ' ' || lower(",
+            $table,
+            ".channel) || ' ', '')) < length(",
+            $chans,
+            "))"
+        )
+    };
+}
+const SQL_PING: &str = "select 1";
+
+const SQL_INSERT_URL: &str = "insert into url (seen, channel, nick, url, msg, kind) \
+    values ($1, $2, $3, $4, $5, $6)";
+
+const SQL_INSERT_META: &str = "insert into url_meta (url_id, lang, title, descr) \
     values ($1, $2, $3, $4)";
-pub async fn db_add_url(db: &mut DbCtx, ur: &UrlCtx) -> Result<u64, sqlx::Error> {
-    let mut rowcnt = 0;
-    let mut retry = 0;
-    while retry < RETRY_CNT {
-        match sqlx::query(SQL_INSERT_URL)
-            .bind(ur.ts)
-            .bind(&ur.chan)
-            .bind(&ur.nick)
-            .bind(&ur.url)
-            .execute(&db.dbc)
+const SQL_INSERT_TOPIC: &str = "insert into topic (seen, channel, nick, topic) \
+    values ($1, $2, $3, $4) \
+    on conflict do nothing";
+const SQL_TOPICS: &str = concat!(
+    "select id, seen, channel, nick, topic from topic \
+    where channel = $1 and seen >= $2 and seen < $3 and ",
+    sql_visible!("topic", "$4", "$5"),
+    " order by seen, id"
+);
+
+macro_rules! sql_nometa {
+    ($order:literal) => {
+        concat!(
+            "select url.id, url.url, url.seen ",
+            "from url ",
+            "where not exists (",
+            "select null ",
+            "from url_meta ",
+            "where url.id = url_meta.url_id ",
+            ") ",
+            "order by seen ",
+            $order,
+            " limit $1"
+        )
+    };
+}
+
+const SQL_NOMETA_ASC: &str = sql_nometa!("asc");
+const SQL_NOMETA_DESC: &str = sql_nometa!("desc");
+const SQL_NOMETA_COUNT: &str = "select count(*) from url \
+    where not exists (select null from url_meta where url.id = url_meta.url_id)";
+
+// The grouped sightings with msg and kind of the earliest one, ties broken by id. Backfills and spool replays
+// do not insert in time order, so the ids alone do not tell. The sightings are ranked in a subquery and the
+// first one joined afterwards, as SQLite cannot use an aggregate in a subquery.
+macro_rules! sql_first_msg {
+    ($keys:literal, $columns:literal, $filtered:expr, $having:literal, $order:literal) => {
+        concat!(
+            "select g.*, f.msg, f.kind from (select ",
+            $columns,
+            ", min(case when rn = 1 then id end) as first_id from (select url.*, url_meta.title, ",
+            "row_number() over (partition by ",
+            $keys,
+            " order by url.seen, url.id) as rn ",
+            $filtered,
+            ") as u group by ",
+            $keys,
+            " ",
+            $having,
+            ") as g inner join url as f on f.id = g.first_id ",
+            $order
+        )
+    };
+}
+
+const SQL_SEARCH: &str = sql_first_msg!(
+    "url",
+    "min(id) as id, min(seen) as seen_first, max(seen) as seen_last, count(seen) as seen_cnt, \
+    string_agg(channel, ' ') as channel, string_agg(nick, ' ') as nick, \
+    url, max(title) as title",
+    concat!(
+        "from url \
+        inner join url_meta on url_meta.url_id = url.id \
+        where lower(channel) like $1 \
+        and lower(nick) like $2 \
+        and lower(url) like $3 \
+        and lower(url_meta.title) like $4 \
+        and lower(msg) like $5 \
+        and kind like $6 \
+        and ",
+        sql_visible!("url", "$7", "$8")
+    ),
+    "",
+    "order by g.seen_last desc limit 255"
+);
+const SEARCH_LIMIT: usize = 255;
+
+const SQL_URL: &str = sql_first_msg!(
+    "channel, url",
+    "min(id) as id, min(seen) as seen_first, max(seen) as seen_last, count(seen) as seen_cnt, \
+    channel, string_agg(nick, ' ') as nick, \
+    url, max(title) as title",
+    concat!(
+        "from url \
+        inner join url_meta on url_meta.url_id = url.id \
+        where ",
+        sql_visible!("url", "$2", "$3")
+    ),
+    "having max(seen) > $1",
+    "order by g.seen_last desc"
+);
+
+const SQL_UNIQ: &str = sql_first_msg!(
+    "url",
+    "min(id) as id, min(seen) as seen_first, max(seen) as seen_last, count(seen) as seen_cnt, \
+    string_agg(channel, ' ') as channel, string_agg(nick, ' ') as nick, \
+    url, max(title) as title",
+    concat!(
+        "from url \
+        inner join url_meta on url_meta.url_id = url.id \
+        where ",
+        sql_visible!("url", "$2", "$3")
+    ),
+    "having max(seen) > $1",
+    "order by g.seen_last desc"
+);
+
+const SQL_URL_RANGE: &str = sql_first_msg!(
+    "channel, url",
+    "min(id) as id, min(seen) as seen_first, max(seen) as seen_last, count(seen) as seen_cnt, \
+    channel, string_agg(nick, ' ') as nick, \
+    url, max(title) as title",
+    concat!(
+        "from url \
+        inner join url_meta on url_meta.url_id = url.id \
+        where seen >= $1 and seen < $2 and ",
+        sql_visible!("url", "$3", "$4")
+    ),
+    "",
+    "order by g.seen_first"
+);
+
+const SQL_UNIQ_RANGE: &str = sql_first_msg!(
+    "url",
+    "min(id) as id, min(seen) as seen_first, max(seen) as seen_last, count(seen) as seen_cnt, \
+    string_agg(channel, ' ') as channel, string_agg(nick, ' ') as nick, \
+    url, max(title) as title",
+    concat!(
+        "from url \
+        inner join url_meta on url_meta.url_id = url.id \
+        where seen >= $1 and seen < $2 and ",
+        sql_visible!("url", "$3", "$4")
+    ),
+    "",
+    "order by g.seen_first"
+);
+
+const SQL_SAVE_REMOVED: &str = "insert into url_removed (id, seen, channel, nick, url, msg, kind, removed) \
+    select id, seen, channel, nick, url, msg, kind, $2 from url \
+    where url in (select url from url where id = $1)";
+const SQL_REMOVE_URL: &str = "delete from url where url in (select url from url where id = $1)";
+const SQL_RESTORE_URL: &str = "insert into url (id, seen, channel, nick, url, msg, kind) \
+    select id, seen, channel, nick, url, msg, kind from url_removed \
+    where url in (select url from url_removed where id = $1)";
+const SQL_FORGET_REMOVED: &str = "delete from url_removed where url in (select url from url_removed where id = $1)";
+const SQL_URL_SIGHTINGS: &str = "select id, seen, channel, nick, url, msg, kind from url \
+    where url in (select url from url where id = $1) \
+    order by seen, id";
+const SQL_URL_META: &str = "select id, url_id, lang, title, descr from url_meta \
+    where url_id in (select id from url where url in (select url from url where id = $1)) \
+    order by url_id";
+const SQL_REMOVE_META: &str = "delete from url_meta where url_id = $1";
+const SQL_SIGHTINGS: &str = "select id, seen, channel, nick, url, msg, kind from url \
+    where id > $1 \
+    order by id \
+    limit $2";
+const SQL_SAVE_REMOVED_SIGHTING: &str = "insert into url_removed (id, seen, channel, nick, url, msg, kind, removed) \
+    select id, seen, channel, nick, url, msg, kind, $2 from url where id = $1";
+const SQL_REMOVE_SIGHTING: &str = "delete from url where id = $1";
+
+const SQL_STATS: &str = concat!(
+    "select coalesce(min(seen), 0) as seen_first, coalesce(max(seen), 0) as seen_last, \
+    count(url.id) as n_url, coalesce(max(url.id), 0) as max_id, \
+    count(url_meta.id) as n_meta, coalesce(max(url_meta.id), 0) as max_meta_id, \
+    (select count(*) from topic where topic.seen >= $1 and topic.seen < $2 and ",
+    sql_visible!("topic", "$3", "$4"),
+    ") as n_topic from url \
+    left join url_meta on url_meta.url_id = url.id \
+    where seen >= $1 and seen < $2 and ",
+    sql_visible!("url", "$3", "$4")
+);
+
+const SQL_IMPORT_URL: &str = "insert into url (id, seen, channel, nick, url, msg, kind) \
+    values ($1, $2, $3, $4, $5, $6, $7)";
+// keep the id sequence ahead of the imported ids
+const SQL_SYNC_URL_SEQ: &str = "select setval('url_id_seq', (select max(id) from url))";
+
+impl DbCtx {
+    // Public rows, the privacy parameters follow the args
+    async fn read_url_rows(&self, sql: &'static str, args: &[i64]) -> Result<Vec<DbUrlRow>, sqlx::Error> {
+        let (nicks, chans) = self.privacy_params(Visibility::Public);
+        let mut query = sqlx::query_as::<_, DbUrlRow>(sql);
+        for arg in args {
+            query = query.bind(*arg);
+        }
+        query.bind(nicks).bind(chans).fetch_all(&self.dbc).await
+    }
+
+    async fn insert_sightings(&self, urls: &[UrlCtx]) -> Result<u64, sqlx::Error> {
+        let mut tx = self.dbc.begin().await?;
+        let mut rowcnt = 0;
+        for ur in urls {
+            rowcnt += sqlx::query(SQL_INSERT_URL)
+                .bind(ur.ts)
+                .bind(&ur.chan)
+                .bind(&ur.nick)
+                .bind(&ur.url)
+                .bind(&ur.msg)
+                .bind(ur.kind.as_str())
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+        }
+        tx.commit().await?;
+        Ok(rowcnt)
+    }
+}
+
+impl UrlRepo for DbCtx {
+    async fn set_privacy(&self, privacy: &Privacy) -> Result<(), sqlx::Error> {
+        match self.privacy.write() {
+            Ok(mut p) => *p = privacy.clone(),
+            Err(e) => *e.into_inner() = privacy.clone(),
+        }
+        Ok(())
+    }
+
+    async fn add_sightings(&self, urls: &[UrlCtx]) -> Result<u64, sqlx::Error> {
+        if urls.is_empty() {
+            return Ok(0);
+        }
+        let mut retry = 0;
+        loop {
+            match self.insert_sightings(urls).await {
+                Ok(rowcnt) => {
+                    self.notify_changed();
+                    return Ok(rowcnt);
+                }
+                Err(e) if retry < RETRY_CNT => {
+                    error!("Insert failed: {e:?}");
+                    error!("Retrying in {}s...", RETRY_SLEEP);
+                    sleep(Duration::new(RETRY_SLEEP, 0)).await;
+                    retry += 1;
+                }
+                Err(e) => {
+                    error!("GAVE UP after {RETRY_CNT} retries.");
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    async fn add_meta(&self, m: &MetaCtx) -> Result<u64, sqlx::Error> {
+        let res = sqlx::query(SQL_INSERT_META)
+            .bind(m.url_id)
+            .bind(&m.lang)
+            .bind(&m.title)
+            .bind(&m.descr)
+            .execute(&self.dbc)
+            .await?;
+        self.notify_changed();
+        Ok(res.rows_affected())
+    }
+
+    async fn add_topics(&self, topics: &[TopicCtx]) -> Result<u64, sqlx::Error> {
+        let mut tx = self.dbc.begin().await?;
+        let mut rowcnt = 0;
+        for t in topics {
+            rowcnt += sqlx::query(SQL_INSERT_TOPIC)
+                .bind(t.ts)
+                .bind(&t.chan)
+                .bind(&t.nick)
+                .bind(&t.topic)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+        }
+        tx.commit().await?;
+        if rowcnt > 0 {
+            self.notify_changed();
+        }
+        Ok(rowcnt)
+    }
+
+    async fn topics(&self, chan: &str, ts_start: i64, ts_end: i64) -> Result<Vec<DbTopic>, sqlx::Error> {
+        let (nicks, chans) = self.privacy_params(Visibility::Public);
+        sqlx::query_as::<_, DbTopic>(SQL_TOPICS)
+            .bind(chan)
+            .bind(ts_start)
+            .bind(ts_end)
+            .bind(nicks)
+            .bind(chans)
+            .fetch_all(&self.dbc)
+            .await
+    }
+
+    async fn pending_meta(&self, order: SortOrder, limit: usize) -> Result<Vec<DbNoMeta>, sqlx::Error> {
+        let sql = match order {
+            SortOrder::Asc => SQL_NOMETA_ASC,
+            SortOrder::Desc => SQL_NOMETA_DESC,
+        };
+        sqlx::query_as::<_, DbNoMeta>(sql)
+            .bind(limit as i64)
+            .fetch_all(&self.dbc)
+            .await
+    }
+
+    async fn pending_meta_count(&self) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar::<_, i64>(SQL_NOMETA_COUNT)
+            .fetch_one(&self.dbc)
+            .await
+    }
+
+    async fn search(&self, q: &SearchQuery) -> Result<Vec<DbUrlRow>, sqlx::Error> {
+        let (nicks, chans) = self.privacy_params(Visibility::SearchOnly);
+        sqlx::query_as::<_, DbUrlRow>(SQL_SEARCH)
+            .bind(&q.chan)
+            .bind(&q.nick)
+            .bind(&q.url)
+            .bind(&q.title)
+            .bind(&q.msg)
+            .bind(&q.kind)
+            .bind(nicks)
+            .bind(chans)
+            .fetch_all(&self.dbc)
             .await
-        {
-            Ok(res) => {
-                info!("Insert result: {res:#?}");
-                retry = 0;
-                rowcnt = res.rows_affected();
-                break;
+    }
+
+    async fn recent(&self, ts_limit: i64, grouping: UrlGrouping) -> Result<Vec<DbUrlRow>, sqlx::Error> {
+        let sql = match grouping {
+            UrlGrouping::PerChannel => SQL_URL,
+            UrlGrouping::Uniq => SQL_UNIQ,
+        };
+        self.read_url_rows(sql, &[ts_limit]).await
+    }
+
+    async fn range(&self, ts_start: i64, ts_end: i64, grouping: UrlGrouping) -> Result<Vec<DbUrlRow>, sqlx::Error> {
+        let sql = match grouping {
+            UrlGrouping::PerChannel => SQL_URL_RANGE,
+            UrlGrouping::Uniq => SQL_UNIQ_RANGE,
+        };
+        self.read_url_rows(sql, &[ts_start, ts_end]).await
+    }
+
+    async fn remove_url(&self, id: i32) -> Result<u64, sqlx::Error> {
+        let mut tx = self.dbc.begin().await?;
+        sqlx::query(SQL_SAVE_REMOVED)
+            .bind(id)
+            .bind(Utc::now().timestamp())
+            .execute(&mut *tx)
+            .await?;
+        let res = sqlx::query(SQL_REMOVE_URL).bind(id).execute(&mut *tx).await?;
+        tx.commit().await?;
+        self.notify_changed();
+        Ok(res.rows_affected())
+    }
+
+    async fn restore_url(&self, id: i32) -> Result<u64, sqlx::Error> {
+        let mut tx = self.dbc.begin().await?;
+        let res = sqlx::query(SQL_RESTORE_URL).bind(id).execute(&mut *tx).await?;
+        sqlx::query(SQL_FORGET_REMOVED).bind(id).execute(&mut *tx).await?;
+        tx.commit().await?;
+        self.notify_changed();
+        Ok(res.rows_affected())
+    }
+
+    async fn url_sightings(&self, id: i32) -> Result<Vec<DbUrl>, sqlx::Error> {
+        sqlx::query_as::<_, DbUrl>(SQL_URL_SIGHTINGS)
+            .bind(id)
+            .fetch_all(&self.dbc)
+            .await
+    }
+
+    async fn url_meta(&self, id: i32) -> Result<Vec<DbMeta>, sqlx::Error> {
+        sqlx::query_as::<_, DbMeta>(SQL_URL_META)
+            .bind(id)
+            .fetch_all(&self.dbc)
+            .await
+    }
+
+    async fn remove_meta(&self, url_id: i32) -> Result<u64, sqlx::Error> {
+        let res = sqlx::query(SQL_REMOVE_META).bind(url_id).execute(&self.dbc).await?;
+        self.notify_changed();
+        Ok(res.rows_affected())
+    }
+
+    async fn sightings(&self, after_id: i32, limit: usize) -> Result<Vec<DbUrl>, sqlx::Error> {
+        sqlx::query_as::<_, DbUrl>(SQL_SIGHTINGS)
+            .bind(after_id)
+            .bind(limit as i64)
+            .fetch_all(&self.dbc)
+            .await
+    }
+
+    async fn remove_sightings(&self, ids: &[i32]) -> Result<u64, sqlx::Error> {
+        let mut tx = self.dbc.begin().await?;
+        let mut rowcnt = 0;
+        let removed = Utc::now().timestamp();
+        for id in ids {
+            sqlx::query(SQL_SAVE_REMOVED_SIGHTING)
+                .bind(id)
+                .bind(removed)
+                .execute(&mut *tx)
+                .await?;
+            rowcnt += sqlx::query(SQL_REMOVE_SIGHTING)
+                .bind(id)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+        }
+        tx.commit().await?;
+        if rowcnt > 0 {
+            self.notify_changed();
+        }
+        Ok(rowcnt)
+    }
+
+    async fn stats(&self, ts_start: i64, ts_end: i64) -> Result<DbStats, sqlx::Error> {
+        let (nicks, chans) = self.privacy_params(Visibility::Public);
+        sqlx::query_as::<_, DbStats>(SQL_STATS)
+            .bind(ts_start)
+            .bind(ts_end)
+            .bind(nicks)
+            .bind(chans)
+            .fetch_one(&self.dbc)
+            .await
+    }
+
+    async fn import_urls(&self, urls: &[DbUrl]) -> Result<u64, sqlx::Error> {
+        let mut tx = self.dbc.begin().await?;
+        let mut rowcnt = 0;
+        for u in urls {
+            rowcnt += sqlx::query(SQL_IMPORT_URL)
+                .bind(u.id)
+                .bind(u.seen)
+                .bind(&u.channel)
+                .bind(&u.nick)
+                .bind(&u.url)
+                .bind(&u.msg)
+                .bind(if u.kind.is_empty() {
+                    MsgKind::default().as_str()
+                } else {
+                    &u.kind
+                })
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+        }
+        // SQLite autoincrement keeps up with explicit ids by itself
+        if self.backend == DbBackend::Postgres && rowcnt > 0 {
+            sqlx::query(SQL_SYNC_URL_SEQ).execute(&mut *tx).await?;
+        }
+        tx.commit().await?;
+        self.notify_changed();
+        Ok(rowcnt)
+    }
+
+    async fn import_meta(&self, meta: &[MetaCtx]) -> Result<u64, sqlx::Error> {
+        let mut tx = self.dbc.begin().await?;
+        let mut rowcnt = 0;
+        for m in meta {
+            rowcnt += sqlx::query(SQL_INSERT_META)
+                .bind(m.url_id)
+                .bind(&m.lang)
+                .bind(&m.title)
+                .bind(&m.descr)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+        }
+        tx.commit().await?;
+        self.notify_changed();
+        Ok(rowcnt)
+    }
+}
+
+// Bulk loading of history, re-runs skip the sightings that are already in the database.
+// PostgreSQL gets the rows with COPY into a temporary table on a dedicated connection,
+// SQLite falls back to batched inserts. Everything happens in one transaction.
+const BULK_BATCH: usize = 8192;
+
+const SQL_BULK_TEMP: &str = "create temporary table url_import \
+    (seen bigint not null, channel text not null, nick text not null, url text not null, \
+    msg text not null, kind text not null) \
+    on commit drop";
+const SQL_BULK_COPY: &str = "copy url_import (seen, channel, nick, url, msg, kind) from stdin";
+// Repeated postings within the import are kept, only the sightings stored before it started are skipped
+const SQL_BULK_MERGE: &str = "insert into url (seen, channel, nick, url, msg, kind) \
+    select i.seen, i.channel, i.nick, i.url, i.msg, i.kind \
+    from url_import i \
+    where not exists (select null from url \
+    where url.seen = i.seen and url.channel = i.channel and url.nick = i.nick and url.url = i.url \
+    and url.id <= $1)";
+const SQL_BULK_CLEAR: &str = "truncate url_import";
+const SQL_BULK_MAX_ID: &str = "select cast(coalesce(max(id), 0) as bigint) from url";
+
+const SQL_INSERT_URL_NEW: &str = "insert into url (seen, channel, nick, url, msg, kind) \
+    select $1, $2, $3, $4, $5, $6 \
+    where not exists (select null from url \
+    where seen = $1 and channel = $2 and nick = $3 and url = $4 and id <= $7)";
+
+enum BulkConn {
+    Postgres(PgConnection),
+    Sqlite(AnyConnection),
+}
+
+pub struct BulkImport<'a> {
+    db: &'a DbCtx,
+    conn: BulkConn,
+    pending: Vec<UrlCtx>,
+    // the last sighting stored before the import
+    max_id: i64,
+    pub n_sent: u64,
+    pub n_inserted: u64,
+}
+
+impl DbCtx {
+    pub async fn bulk_import(&self) -> Result<BulkImport<'_>, sqlx::Error> {
+        let (conn, max_id) = match self.backend {
+            DbBackend::Postgres => {
+                let mut conn = PgConnection::connect(self.db_url.expose()).await?;
+                conn.execute("begin").await?;
+                conn.execute(SQL_BULK_TEMP).await?;
+                let (max_id,): (i64,) = sqlx::query_as(SQL_BULK_MAX_ID).fetch_one(&mut conn).await?;
+                (BulkConn::Postgres(conn), max_id)
             }
-            Err(e) => {
-                error!("Insert failed: {e:?}");
+            DbBackend::Sqlite => {
+                let mut conn = AnyConnection::connect(self.db_url.expose()).await?;
+                conn.execute(SQLITE_PRAGMAS).await?;
+                conn.execute("begin immediate").await?;
+                let (max_id,): (i64,) = sqlx::query_as(SQL_BULK_MAX_ID).fetch_one(&mut conn).await?;
+                (BulkConn::Sqlite(conn), max_id)
             }
+        };
+        Ok(BulkImport {
+            db: self,
+            conn,
+            pending: Vec::with_capacity(BULK_BATCH),
+            max_id,
+            n_sent: 0,
+            n_inserted: 0,
+        })
+    }
+}
+
+impl BulkImport<'_> {
+    pub async fn add(&mut self, urls: Vec<UrlCtx>) -> Result<(), sqlx::Error> {
+        self.pending.extend(urls);
+        if self.pending.len() >= BULK_BATCH {
+            self.flush().await?;
         }
-        error!("Retrying in {}s...", RETRY_SLEEP);
-        sleep(Duration::new(RETRY_SLEEP, 0)).await;
-        retry += 1;
+        Ok(())
     }
-    if retry > 0 {
-        error!("GAVE UP after {RETRY_CNT} retries.");
+
+    async fn flush(&mut self) -> Result<(), sqlx::Error> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        match &mut self.conn {
+            BulkConn::Postgres(conn) => {
+                let mut buf = Vec::with_capacity(self.pending.len() * 128);
+                for u in &self.pending {
+                    buf.extend_from_slice(
+                        format!(
+                            "{}\t{}\t{}\t{}\t{}\t{}\n",
+                            u.ts,
+                            copy_text(&u.chan),
+                            copy_text(&u.nick),
+                            copy_text(&u.url),
+                            copy_text(&u.msg),
+                            u.kind
+                        )
+                        .as_bytes(),
+                    );
+                }
+                let mut copy = conn.copy_in_raw(SQL_BULK_COPY).await?;
+                copy.send(buf).await?;
+                copy.finish().await?;
+                self.n_inserted += sqlx::query(SQL_BULK_MERGE)
+                    .bind(self.max_id)
+                    .execute(&mut *conn)
+                    .await?
+                    .rows_affected();
+                conn.execute(SQL_BULK_CLEAR).await?;
+            }
+            BulkConn::Sqlite(conn) => {
+                for u in &self.pending {
+                    self.n_inserted += sqlx::query(SQL_INSERT_URL_NEW)
+                        .bind(u.ts)
+                        .bind(&u.chan)
+                        .bind(&u.nick)
+                        .bind(&u.url)
+                        .bind(&u.msg)
+                        .bind(u.kind.as_str())
+                        .bind(self.max_id)
+                        .execute(&mut *conn)
+                        .await?
+                        .rows_affected();
+                }
+            }
+        }
+        self.n_sent += self.pending.len() as u64;
+        self.pending.clear();
+        Ok(())
+    }
+
+    // Returns the number of new sightings
+    pub async fn finish(mut self) -> Result<u64, sqlx::Error> {
+        self.flush().await?;
+        match self.conn {
+            BulkConn::Postgres(mut conn) => {
+                conn.execute("commit").await?;
+                conn.close().await?;
+            }
+            BulkConn::Sqlite(mut conn) => {
+                conn.execute("commit").await?;
+                conn.close().await?;
+            }
+        }
+        self.db.notify_changed();
+        Ok(self.n_inserted)
     }
-    Ok(rowcnt)
 }
 
-const SQL_INSERT_META: &str = "insert into url_meta (url_id, lang, title, descr) \
-        values ($1, $2, $3, $4)";
-pub async fn db_add_meta(db: &DbCtx, m: &MetaCtx) -> Result<u64, sqlx::Error> {
-    let res = sqlx::query(SQL_INSERT_META)
-        .bind(m.url_id)
-        .bind(&m.lang)
-        .bind(&m.title)
-        .bind(&m.descr)
-        .execute(&db.dbc)
-        .await?;
-    Ok(res.rows_affected())
+// Escape a value for the COPY text format
+fn copy_text(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\t' => out.push_str("\\t"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[derive(Debug, Default)]
+struct MemData {
+    urls: Vec<DbUrl>,
+    removed: Vec<DbUrl>,
+    meta: Vec<DbMeta>,
+    topics: Vec<DbTopic>,
+    privacy: Privacy,
+    next_url_id: i32,
+    next_meta_id: i32,
+    // add_sightings calls that succeed before the database "goes away"
+    sighting_writes_left: Option<usize>,
+}
+
+// A constraint violation in MemRepo, reported like the database would
+#[derive(Debug, Clone, Copy)]
+enum MemConstraint {
+    Unique,
+    ForeignKey,
 }
+
+#[derive(Debug)]
+struct MemConstraintError {
+    message: String,
+    kind: MemConstraint,
+}
+
+impl MemConstraintError {
+    fn violation(kind: MemConstraint, message: String) -> sqlx::Error {
+        sqlx::Error::Database(Box::new(Self { message, kind }))
+    }
+}
+
+impl fmt::Display for MemConstraintError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for MemConstraintError {}
+
+impl sqlx::error::DatabaseError for MemConstraintError {
+    fn message(&self) -> &str {
+        &self.message
+    }
+
+    fn as_error(&self) -> &(dyn std::error::Error + Send + Sync + 'static) {
+        self
+    }
+
+    fn as_error_mut(&mut self) -> &mut (dyn std::error::Error + Send + Sync + 'static) {
+        self
+    }
+
+    fn into_error(self: Box<Self>) -> Box<dyn std::error::Error + Send + Sync + 'static> {
+        self
+    }
+
+    fn kind(&self) -> sqlx::error::ErrorKind {
+        match self.kind {
+            MemConstraint::Unique => sqlx::error::ErrorKind::UniqueViolation,
+            MemConstraint::ForeignKey => sqlx::error::ErrorKind::ForeignKeyViolation,
+        }
+    }
+}
+
+// In-memory UrlRepo with the same semantics as the SQL queries, for tests
+#[derive(Debug, Default)]
+pub struct MemRepo {
+    data: std::sync::Mutex<MemData>,
+}
+
+impl MemRepo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Makes add_sightings fail after n more calls, like an unavailable database
+    pub fn fail_sighting_writes_after(&self, n: usize) {
+        self.data().sighting_writes_left = Some(n);
+    }
+
+    fn data(&self) -> std::sync::MutexGuard<'_, MemData> {
+        match self.data.lock() {
+            Ok(d) => d,
+            Err(e) => e.into_inner(),
+        }
+    }
+
+    // Mimics the grouped "inner join url_meta" queries, rows are shown at `level`
+    fn url_rows<F>(&self, grouping: UrlGrouping, level: Visibility, filter: F) -> Vec<DbUrlRow>
+    where
+        F: Fn(&DbUrl, &DbMeta) -> bool,
+    {
+        let data = self.data();
+        // (channel, url) and the (seen, id) of the first sighting of each row
+        type Group = ((String, String), (i64, i32), DbUrlRow);
+        let mut groups: Vec<Group> = Vec::new();
+        for u in &data.urls {
+            let Some(m) = data.meta.iter().find(|m| m.url_id == u.id as i64) else {
+                continue;
+            };
+            if !filter(u, m) || !data.privacy.shows(&u.channel, &u.nick, level) {
+                continue;
+            }
+            let key = match grouping {
+                UrlGrouping::PerChannel => (u.channel.clone(), u.url.clone()),
+                UrlGrouping::Uniq => (String::new(), u.url.clone()),
+            };
+            match groups.iter_mut().find(|(k, _, _)| *k == key) {
+                Some((_, first, row)) => {
+                    // msg and kind of the earliest sighting, like sql_first_msg
+                    if (u.seen, u.id) < *first {
+                        *first = (u.seen, u.id);
+                        row.msg = u.msg.clone();
+                        row.kind = u.kind.clone();
+                    }
+                    row.id = row.id.min(u.id);
+                    row.seen_first = row.seen_first.min(u.seen);
+                    row.seen_last = row.seen_last.max(u.seen);
+                    row.seen_cnt += 1;
+                    if grouping == UrlGrouping::Uniq {
+                        row.channel = format!("{} {}", row.channel, u.channel);
+                    }
+                    row.nick = format!("{} {}", row.nick, u.nick);
+                    row.title = row.title.clone().max(m.title.clone());
+                }
+                None => groups.push((
+                    key,
+                    (u.seen, u.id),
+                    DbUrlRow {
+                        id: u.id,
+                        seen_first: u.seen,
+                        seen_last: u.seen,
+                        seen_cnt: 1,
+                        channel: u.channel.clone(),
+                        nick: u.nick.clone(),
+                        url: u.url.clone(),
+                        title: m.title.clone(),
+                        msg: u.msg.clone(),
+                        kind: u.kind.clone(),
+                    },
+                )),
+            }
+        }
+        groups.into_iter().map(|(_, _, row)| row).collect()
+    }
+}
+
+// Case insensitive SQL LIKE with the % and _ wildcards
+fn sql_like(pattern: &str, s: &str) -> bool {
+    fn like(p: &[char], s: &[char]) -> bool {
+        match p.split_first() {
+            None => s.is_empty(),
+            Some(('%', rest)) => (0..=s.len()).any(|i| like(rest, &s[i..])),
+            Some(('_', rest)) => !s.is_empty() && like(rest, &s[1..]),
+            Some((c, rest)) => s.first() == Some(c) && like(rest, &s[1..]),
+        }
+    }
+    let p = pattern.to_lowercase().chars().collect::<Vec<_>>();
+    let s = s.to_lowercase().chars().collect::<Vec<_>>();
+    like(&p, &s)
+}
+
+impl UrlRepo for MemRepo {
+    async fn set_privacy(&self, privacy: &Privacy) -> Result<(), sqlx::Error> {
+        self.data().privacy = privacy.clone();
+        Ok(())
+    }
+
+    async fn add_sightings(&self, urls: &[UrlCtx]) -> Result<u64, sqlx::Error> {
+        let mut data = self.data();
+        match data.sighting_writes_left.as_mut() {
+            Some(0) => return Err(sqlx::Error::PoolTimedOut),
+            Some(n) => *n -= 1,
+            None => {}
+        }
+        for ur in urls {
+            data.next_url_id += 1;
+            let id = data.next_url_id;
+            data.urls.push(DbUrl {
+                id,
+                seen: ur.ts,
+                channel: ur.chan.clone(),
+                nick: ur.nick.clone(),
+                url: ur.url.clone(),
+                msg: ur.msg.clone(),
+                kind: ur.kind.to_string(),
+            });
+        }
+        Ok(urls.len() as u64)
+    }
+
+    async fn add_meta(&self, m: &MetaCtx) -> Result<u64, sqlx::Error> {
+        let mut data = self.data();
+        // url_id is unique and references url(id)
+        if data.meta.iter().any(|d| d.url_id == m.url_id as i64) {
+            return Err(MemConstraintError::violation(
+                MemConstraint::Unique,
+                format!(
+                    "duplicate key value violates unique constraint on url_meta(url_id): {}",
+                    m.url_id
+                ),
+            ));
+        }
+        if !data.urls.iter().any(|u| u.id == m.url_id) {
+            return Err(MemConstraintError::violation(
+                MemConstraint::ForeignKey,
+                format!("url_meta(url_id) references a missing url(id): {}", m.url_id),
+            ));
+        }
+        data.next_meta_id += 1;
+        let id = data.next_meta_id;
+        data.meta.push(DbMeta {
+            id,
+            url_id: m.url_id as i64,
+            lang: m.lang.clone(),
+            title: m.title.clone(),
+            descr: m.descr.clone(),
+        });
+        Ok(1)
+    }
+
+    async fn add_topics(&self, topics: &[TopicCtx]) -> Result<u64, sqlx::Error> {
+        let mut data = self.data();
+        let mut rowcnt = 0;
+        for t in topics {
+            let dup = data
+                .topics
+                .iter()
+                .any(|d| d.seen == t.ts && d.channel == t.chan && d.nick == t.nick && d.topic == t.topic);
+            if dup {
+                continue;
+            }
+            let id = data.topics.len() as i32 + 1;
+            data.topics.push(DbTopic {
+                id,
+                seen: t.ts,
+                channel: t.chan.clone(),
+                nick: t.nick.clone(),
+                topic: t.topic.clone(),
+            });
+            rowcnt += 1;
+        }
+        Ok(rowcnt)
+    }
+
+    async fn topics(&self, chan: &str, ts_start: i64, ts_end: i64) -> Result<Vec<DbTopic>, sqlx::Error> {
+        let data = self.data();
+        let mut topics = data
+            .topics
+            .iter()
+            .filter(|t| t.channel == chan && t.seen >= ts_start && t.seen < ts_end)
+            .filter(|t| data.privacy.shows(&t.channel, &t.nick, Visibility::Public))
+            .cloned()
+            .collect::<Vec<_>>();
+        topics.sort_by_key(|t| (t.seen, t.id));
+        Ok(topics)
+    }
+
+    async fn pending_meta(&self, order: SortOrder, limit: usize) -> Result<Vec<DbNoMeta>, sqlx::Error> {
+        let data = self.data();
+        let mut rows = data
+            .urls
+            .iter()
+            .filter(|u| !data.meta.iter().any(|m| m.url_id == u.id as i64))
+            .map(|u| DbNoMeta {
+                id: u.id,
+                url: u.url.clone(),
+                seen: u.seen,
+            })
+            .collect::<Vec<_>>();
+        rows.sort_by_key(|r| r.seen);
+        if order == SortOrder::Desc {
+            rows.reverse();
+        }
+        rows.truncate(limit);
+        Ok(rows)
+    }
+
+    async fn pending_meta_count(&self) -> Result<i64, sqlx::Error> {
+        let data = self.data();
+        let n = data
+            .urls
+            .iter()
+            .filter(|u| !data.meta.iter().any(|m| m.url_id == u.id as i64))
+            .count();
+        Ok(n as i64)
+    }
+
+    async fn search(&self, q: &SearchQuery) -> Result<Vec<DbUrlRow>, sqlx::Error> {
+        let mut rows = self.url_rows(UrlGrouping::Uniq, Visibility::SearchOnly, |u, m| {
+            sql_like(&q.chan, &u.channel)
+                && sql_like(&q.nick, &u.nick)
+                && sql_like(&q.url, &u.url)
+                && sql_like(&q.title, &m.title)
+                && sql_like(&q.msg, &u.msg)
+                && sql_like(&q.kind, &u.kind)
+        });
+        rows.sort_by_key(|r| std::cmp::Reverse(r.seen_last));
+        rows.truncate(SEARCH_LIMIT);
+        Ok(rows)
+    }
+
+    async fn recent(&self, ts_limit: i64, grouping: UrlGrouping) -> Result<Vec<DbUrlRow>, sqlx::Error> {
+        let mut rows = self.url_rows(grouping, Visibility::Public, |_, _| true);
+        rows.retain(|r| r.seen_last > ts_limit);
+        rows.sort_by_key(|r| std::cmp::Reverse(r.seen_last));
+        Ok(rows)
+    }
+
+    async fn range(&self, ts_start: i64, ts_end: i64, grouping: UrlGrouping) -> Result<Vec<DbUrlRow>, sqlx::Error> {
+        let mut rows = self.url_rows(grouping, Visibility::Public, |u, _| {
+            u.seen >= ts_start && u.seen < ts_end
+        });
+        rows.sort_by_key(|r| r.seen_first);
+        Ok(rows)
+    }
+
+    async fn remove_url(&self, id: i32) -> Result<u64, sqlx::Error> {
+        let mut data = self.data();
+        let Some(url) = data.urls.iter().find(|u| u.id == id).map(|u| u.url.clone()) else {
+            return Ok(0);
+        };
+        let (removed, kept) = data.urls.drain(..).partition::<Vec<_>, _>(|u| u.url == url);
+        data.urls = kept;
+        let n_removed = removed.len();
+        data.removed.extend(removed);
+        // on delete cascade
+        let MemData { urls, meta, .. } = &mut *data;
+        meta.retain(|m| urls.iter().any(|u| u.id as i64 == m.url_id));
+        Ok(n_removed as u64)
+    }
+
+    async fn restore_url(&self, id: i32) -> Result<u64, sqlx::Error> {
+        let mut data = self.data();
+        let Some(url) = data.removed.iter().find(|u| u.id == id).map(|u| u.url.clone()) else {
+            return Ok(0);
+        };
+        let (restored, kept) = data.removed.drain(..).partition::<Vec<_>, _>(|u| u.url == url);
+        data.removed = kept;
+        let n_restored = restored.len();
+        data.urls.extend(restored);
+        data.urls.sort_by_key(|u| u.id);
+        Ok(n_restored as u64)
+    }
+
+    async fn url_sightings(&self, id: i32) -> Result<Vec<DbUrl>, sqlx::Error> {
+        let data = self.data();
+        let Some(url) = data.urls.iter().find(|u| u.id == id).map(|u| &u.url) else {
+            return Ok(Vec::new());
+        };
+        let mut rows = data.urls.iter().filter(|u| &u.url == url).cloned().collect::<Vec<_>>();
+        rows.sort_by_key(|u| (u.seen, u.id));
+        Ok(rows)
+    }
+
+    async fn url_meta(&self, id: i32) -> Result<Vec<DbMeta>, sqlx::Error> {
+        let ids = self
+            .url_sightings(id)
+            .await?
+            .iter()
+            .map(|u| u.id as i64)
+            .collect::<HashSet<_>>();
+        let data = self.data();
+        let mut rows = data
+            .meta
+            .iter()
+            .filter(|m| ids.contains(&m.url_id))
+            .cloned()
+            .collect::<Vec<_>>();
+        rows.sort_by_key(|m| m.url_id);
+        Ok(rows)
+    }
+
+    async fn remove_meta(&self, url_id: i32) -> Result<u64, sqlx::Error> {
+        let mut data = self.data();
+        let n_before = data.meta.len();
+        data.meta.retain(|m| m.url_id != url_id as i64);
+        Ok((n_before - data.meta.len()) as u64)
+    }
+
+    async fn sightings(&self, after_id: i32, limit: usize) -> Result<Vec<DbUrl>, sqlx::Error> {
+        let mut rows = self
+            .data()
+            .urls
+            .iter()
+            .filter(|u| u.id > after_id)
+            .cloned()
+            .collect::<Vec<_>>();
+        rows.sort_by_key(|u| u.id);
+        rows.truncate(limit);
+        Ok(rows)
+    }
+
+    async fn remove_sightings(&self, ids: &[i32]) -> Result<u64, sqlx::Error> {
+        let mut data = self.data();
+        let (removed, kept) = data.urls.drain(..).partition::<Vec<_>, _>(|u| ids.contains(&u.id));
+        data.urls = kept;
+        let n_removed = removed.len();
+        data.removed.extend(removed);
+        let MemData { urls, meta, .. } = &mut *data;
+        meta.retain(|m| urls.iter().any(|u| u.id as i64 == m.url_id));
+        Ok(n_removed as u64)
+    }
+
+    async fn stats(&self, ts_start: i64, ts_end: i64) -> Result<DbStats, sqlx::Error> {
+        let data = self.data();
+        let mut stats = DbStats::default();
+        let shown = |seen: i64, chan: &str, nick: &str| {
+            seen >= ts_start && seen < ts_end && data.privacy.shows(chan, nick, Visibility::Public)
+        };
+        for u in data.urls.iter().filter(|u| shown(u.seen, &u.channel, &u.nick)) {
+            stats.seen_first = if stats.n_url == 0 {
+                u.seen
+            } else {
+                stats.seen_first.min(u.seen)
+            };
+            stats.seen_last = stats.seen_last.max(u.seen);
+            stats.n_url += 1;
+            stats.max_id = stats.max_id.max(u.id);
+            if let Some(m) = data.meta.iter().find(|m| m.url_id == u.id as i64) {
+                stats.n_meta += 1;
+                stats.max_meta_id = stats.max_meta_id.max(m.id);
+            }
+        }
+        stats.n_topic = data
+            .topics
+            .iter()
+            .filter(|t| shown(t.seen, &t.channel, &t.nick))
+            .count() as i64;
+        Ok(stats)
+    }
+
+    async fn import_urls(&self, urls: &[DbUrl]) -> Result<u64, sqlx::Error> {
+        let mut data = self.data();
+        for u in urls {
+            data.next_url_id = data.next_url_id.max(u.id);
+            data.urls.push(u.clone());
+        }
+        Ok(urls.len() as u64)
+    }
+
+    async fn import_meta(&self, meta: &[MetaCtx]) -> Result<u64, sqlx::Error> {
+        for m in meta {
+            self.add_meta(m).await?;
+        }
+        Ok(meta.len() as u64)
+    }
+}
+
+// A privmsg sighting without a message, for the tests
+#[cfg(test)]
+pub fn test_sighting(ts: i64, chan: &str, nick: &str, url: &str) -> UrlCtx {
+    UrlCtx {
+        ts,
+        chan: chan.to_owned(),
+        nick: nick.to_owned(),
+        url: url.to_owned(),
+        msg: String::new(),
+        kind: MsgKind::Privmsg,
+    }
+}
+
+// Metadata titled "Title <id>" for the given sightings, for the tests
+#[cfg(test)]
+pub async fn add_test_meta<R: UrlRepo>(repo: &R, url_ids: impl IntoIterator<Item = i32>) {
+    for url_id in url_ids {
+        let meta = MetaCtx {
+            url_id,
+            lang: "en".to_owned(),
+            title: format!("Title {url_id}"),
+            descr: String::new(),
+        };
+        repo.add_meta(&meta).await.unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn sqlite_bulk_import_leaves_the_pool_free() {
+        let dir = std::env::temp_dir().join(format!("urlharvest-db-test.{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let cfg = test_config(serde_json::json!({
+            "db_url": format!("sqlite:{}", dir.join("bulk.db").to_string_lossy()),
+            "db_acquire_timeout": 1
+        }));
+        let db = start_db(&cfg).await.unwrap();
+        let url = test_sighting(100, "#42", "a", "https://one.example");
+
+        let mut import = db.bulk_import().await.unwrap();
+        import.add(vec![url.clone(), url]).await.unwrap();
+        import.flush().await.unwrap();
+        // the import is not committed yet, but the readers go on
+        assert_eq!(db.stats(i64::MIN, i64::MAX).await.unwrap().n_url, 0);
+        assert_eq!(import.finish().await.unwrap(), 2);
+        assert_eq!(db.stats(i64::MIN, i64::MAX).await.unwrap().n_url, 2);
+        db.dbc.close().await;
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn mem_repo_reads_and_writes() {
+        let repo = MemRepo::new();
+        let sighting = |ts, chan, nick, url| UrlCtx {
+            msg: format!("{nick}: {url}"),
+            ..test_sighting(ts, chan, nick, url)
+        };
+        repo.add_sightings(&[
+            sighting(100, "#42", "a", "https://one.example"),
+            sighting(200, "#other", "b", "https://one.example"),
+            sighting(300, "#42", "c", "https://two.example"),
+            sighting(400, "#42", "d", "https://no-meta.example"),
+        ])
+        .await
+        .unwrap();
+        add_test_meta(&repo, 1..=3).await;
+        // url_meta(url_id) is unique and references url(id) like in the database
+        for (url_id, unique) in [(1, true), (5, false)] {
+            let meta = MetaCtx {
+                url_id,
+                lang: "en".to_owned(),
+                title: "Again".to_owned(),
+                descr: String::new(),
+            };
+            let err = repo.add_meta(&meta).await.unwrap_err();
+            let db_err = err.as_database_error().unwrap();
+            assert_eq!(
+                (db_err.is_unique_violation(), db_err.is_foreign_key_violation()),
+                (unique, !unique),
+                "{err}"
+            );
+        }
+
+        let pending = repo.pending_meta(SortOrder::Asc, 10).await.unwrap();
+        assert_eq!(pending.iter().map(|r| r.id).collect::<Vec<_>>(), [4]);
+
+        // URLs without metadata are not shown, most recent first
+        let rows = repo.recent(150, UrlGrouping::PerChannel).await.unwrap();
+        let uniq = repo.recent(150, UrlGrouping::Uniq).await.unwrap();
+        let rows = rows.iter().map(|r| (r.channel.as_str(), r.id)).collect::<Vec<_>>();
+        assert_eq!(rows, [("#42", 3), ("#other", 2)]);
+        assert_eq!(uniq.len(), 2);
+        assert_eq!(uniq[1].channel, "#42 #other");
+        assert_eq!(uniq[1].seen_cnt, 2);
+        // the message is the one from the first sighting
+        assert_eq!(uniq[1].msg, "a: https://one.example");
+        assert_eq!(uniq[0].url, "https://two.example");
+
+        let stats = repo.stats(i64::MIN, i64::MAX).await.unwrap();
+        assert_eq!(
+            (stats.seen_first, stats.seen_last, stats.n_url, stats.n_meta),
+            (100, 400, 4, 3)
+        );
+
+        // re-reading the same topic change does not record it twice
+        let topic = TopicCtx {
+            ts: 250,
+            chan: "#42".to_owned(),
+            nick: "a".to_owned(),
+            topic: "news https://one.example".to_owned(),
+        };
+        assert_eq!(repo.add_topics(&[topic.clone(), topic]).await.unwrap(), 1);
+        assert_eq!(repo.topics("#42", 0, 300).await.unwrap().len(), 1);
+        assert!(repo.topics("#other", 0, 300).await.unwrap().is_empty());
+        assert_eq!(repo.stats(200, 300).await.unwrap().n_topic, 1);
+
+        // removing a URL removes all of its sightings and their metadata
+        assert_eq!(repo.remove_url(2).await.unwrap(), 2);
+        let query = SearchQuery {
+            chan: "%".sql_search(),
+            nick: "%".sql_search(),
+            url: "*example".sql_search(),
+            title: "title ?".sql_search(),
+            msg: "c: *".sql_search(),
+            kind: "privmsg".sql_search(),
+        };
+        assert_eq!(
+            repo.search(&query)
+                .await
+                .unwrap()
+                .iter()
+                .map(|r| r.id)
+                .collect::<Vec<_>>(),
+            [3]
+        );
+        assert_eq!(repo.stats(i64::MIN, i64::MAX).await.unwrap().n_meta, 1);
+    }
+
+    // A backfill inserts older sightings after the newer ones
+    async fn first_message_is_the_earliest<R: UrlRepo>(repo: &R) {
+        let sighting = |ts, nick| UrlCtx {
+            msg: format!("{nick} {ts}"),
+            ..test_sighting(ts, "#42", nick, "https://one.example")
+        };
+        repo.add_sightings(&[sighting(300, "live"), sighting(100, "b"), sighting(100, "a")])
+            .await
+            .unwrap();
+        add_test_meta(repo, 1..=3).await;
+
+        let rows = repo.recent(0, UrlGrouping::PerChannel).await.unwrap();
+        let uniq = repo.recent(0, UrlGrouping::Uniq).await.unwrap();
+        assert_eq!((rows[0].seen_first, rows[0].msg.as_str()), (100, "b 100"));
+        assert_eq!(uniq[0].msg, "b 100");
+    }
+
+    #[tokio::test]
+    async fn first_message_by_time() {
+        first_message_is_the_earliest(&MemRepo::new()).await;
+
+        let cfg = test_config(serde_json::json!({}));
+        first_message_is_the_earliest(&start_db(&cfg).await.unwrap()).await;
+    }
+
+    async fn privacy_hides_channels_and_nicks<R: UrlRepo>(repo: &R) {
+        let sighting = |ts, chan, nick| test_sighting(ts, chan, nick, "https://one.example");
+        repo.add_sightings(&[
+            sighting(100, "#public", "a"),
+            sighting(200, "#Search", "b"),
+            sighting(300, "#hidden", "c"),
+            sighting(400, "#public", "OptOut"),
+            sighting(500, "#public", "out"),
+        ])
+        .await
+        .unwrap();
+        add_test_meta(repo, 1..=5).await;
+        let mut privacy = Privacy {
+            channels: std::collections::BTreeMap::from([
+                ("#search".to_owned(), Visibility::SearchOnly),
+                ("#hidden".to_owned(), Visibility::Hidden),
+            ]),
+            nick_optout: std::collections::BTreeSet::from(["optout".to_owned()]),
+        };
+        repo.set_privacy(&privacy).await.unwrap();
+
+        let rows = repo.recent(0, UrlGrouping::PerChannel).await.unwrap();
+        let uniq = repo.recent(0, UrlGrouping::Uniq).await.unwrap();
+        assert_eq!(rows.iter().map(|r| r.channel.as_str()).collect::<Vec<_>>(), ["#public"]);
+        assert_eq!((uniq[0].nick.as_str(), uniq[0].seen_cnt), ("a out", 2));
+
+        let query = SearchQuery {
+            chan: "".sql_search(),
+            nick: "".sql_search(),
+            url: "".sql_search(),
+            title: "".sql_search(),
+            msg: "".sql_search(),
+            kind: "".sql_search(),
+        };
+        let found = repo.search(&query).await.unwrap();
+        assert_eq!(
+            (found[0].channel.as_str(), found[0].seen_cnt),
+            ("#public #Search #public", 3)
+        );
+        assert_eq!(repo.stats(i64::MIN, i64::MAX).await.unwrap().n_url, 2);
+
+        // hidden by default, only the listed channels shown
+        privacy.channels.insert("*".to_owned(), Visibility::Hidden);
+        privacy.channels.insert("#public".to_owned(), Visibility::Public);
+        repo.set_privacy(&privacy).await.unwrap();
+        let found = repo.search(&query).await.unwrap();
+        assert_eq!(
+            (found[0].channel.as_str(), found[0].seen_cnt),
+            ("#public #Search #public", 3)
+        );
+        privacy.channels.remove("#search");
+        repo.set_privacy(&privacy).await.unwrap();
+        let found = repo.search(&query).await.unwrap();
+        assert_eq!((found[0].channel.as_str(), found[0].seen_cnt), ("#public #public", 2));
+        privacy.nick_optout.clear();
+        repo.set_privacy(&privacy).await.unwrap();
+        assert_eq!(repo.stats(i64::MIN, i64::MAX).await.unwrap().n_url, 3);
+    }
+
+    #[tokio::test]
+    async fn privacy_filters() {
+        privacy_hides_channels_and_nicks(&MemRepo::new()).await;
+
+        let cfg = test_config(serde_json::json!({}));
+        privacy_hides_channels_and_nicks(&start_db(&cfg).await.unwrap()).await;
+    }
+}
+
 // EOF
@@ -0,0 +1,1145 @@
+// generate_cmd.rs
+
+use enum_iterator::Sequence;
+use itertools::Itertools;
+use tera::Tera;
+
+use crate::*;
+
+// the status_listen key
+pub const DAEMON: &str = "urllog_generator";
+// defaults for the [generate] settings, in seconds
+const URL_EXPIRE: u64 = 7 * 24 * 3600;
+const VEC_SZ: usize = 4096;
+const TPL_SUFFIX: &str = ".tera";
+const COALESCE_DELAY: u64 = 2;
+// stands in for last_change while rendering, nothing the templates escape
+const LAST_CHANGE_PLACEHOLDER: &str = "urlharvest-last-change-2d41f7";
+
+const ARCHIVE_STATE: &str = "archive-state.json";
+const ARCHIVE_TPL_MONTH: &str = "month.html.tera";
+const ARCHIVE_TPL_INDEX: &str = "index.html.tera";
+// Timezone lookup key in template_timezone for the month boundaries
+const ARCHIVE_TZ_KEY: &str = "archive";
+// Bumped when the per-channel file names change, so that the months get written again
+const ARCHIVE_FILE_NAMING: u32 = 1;
+
+const EXPORT_SCHEMA: &str = "urlharvest.urls";
+const EXPORT_VERSION: u32 = 1;
+const EXPORT_CSV_HEADER: [&str; 8] = [
+    "id",
+    "seen_first",
+    "seen_last",
+    "seen_count",
+    "channels",
+    "nicks",
+    "url",
+    "title",
+];
+
+// Generates the pages, exports and archive, and again whenever the database changes
+pub async fn run(cfg: &ConfigCommon) -> anyhow::Result<()> {
+    check_cmd::startup(cfg, DAEMON).await?;
+    let dbc = start_db(cfg).await?;
+    let shutdown = Shutdown::listen(cfg)?;
+    start_status(cfg, DAEMON, &dbc);
+
+    let mut cfg = cfg.clone();
+    let mut templates = load_templates(&cfg).await?;
+    let mut reload = Reload::listen()?;
+
+    install_static_assets(&cfg.html_dir)?;
+    let mut output = OutputWriter::new(&cfg);
+
+    let mut listener = dbc.listener().await?;
+    health().set_ready();
+
+    loop {
+        health().beat();
+        let coalesce_delay = Duration::new(cfg.generate.coalesce_delay.unwrap_or(COALESCE_DELAY), 0);
+        if let Err(e) = generate_pages(&dbc, &templates.pages, &cfg, &mut output).await {
+            error!("Page generate error: {e}");
+            sleep(coalesce_delay).await;
+            continue;
+        }
+        if let Some(archive) = templates.archive.as_ref()
+            && let Err(e) = generate_archive(&dbc, archive, &cfg, &mut output).await
+        {
+            error!("Archive generate error: {e}");
+        }
+        if let Err(e) = output.write_state() {
+            error!("Output state write error: {e}");
+        }
+
+        info!("Waiting for database updates");
+        tokio::select! {
+            res = wait_for_change(&mut listener, &dbc) => {
+                if res? == DbChange::Reconnected {
+                    warn!("Database listener reconnected; reconciling current state");
+                }
+
+                // Coalesce a burst of writes into one complete regeneration.
+                sleep(coalesce_delay).await;
+                listener.drain();
+            }
+            _ = reload.requested() => {
+                let Some(new_cfg) = reload_config(&cfg, DAEMON, &dbc).await else {
+                    continue;
+                };
+                // the config and the templates change together or not at all
+                match load_templates(&new_cfg).await {
+                    Ok(new_templates) => {
+                        if let Err(e) = install_static_assets(&new_cfg.html_dir) {
+                            error!("Static asset install failed: {e}");
+                        }
+                        templates = new_templates;
+                        output = OutputWriter::new(&new_cfg);
+                        cfg = new_cfg;
+                    }
+                    Err(e) => error!("Template reload failed, keeping the current config and templates: {e}"),
+                }
+            }
+            // a generation pass is never cut short, the pages and the archive state stay consistent
+            _ = shutdown.requested() => break,
+        }
+    }
+    Ok(())
+}
+
+async fn generate_pages<R: UrlRepo>(
+    repo: &R,
+    tera: &Tera,
+    cfg: &ConfigCommon,
+    output: &mut OutputWriter,
+) -> anyhow::Result<()> {
+    let mut now = Utc::now();
+    let ts_limit = now.timestamp() - cfg.generate.url_expire.unwrap_or(URL_EXPIRE) as i64;
+    info!("Generating URL logs starting from {}", ts_limit.ts_long());
+    let (db_data, db_data_uniq) = read_db(repo, ts_limit).await?;
+    info!(
+        "Database read took {} ms.",
+        Utc::now().signed_duration_since(now).num_milliseconds()
+    );
+
+    now = Utc::now();
+    let html_dir = &cfg.html_dir;
+    for template in tera.get_template_names() {
+        let basename = template.strip_suffix(TPL_SUFFIX).unwrap_or(template);
+        let filename_out = format!("{html_dir}/{basename}");
+        let tz = template_tz(cfg, basename);
+
+        info!("Generating {filename_out} from {template}");
+        let mut ctx = generate_ctx(&db_data, &db_data_uniq, tz).await?;
+        if !render_page(tera, template, &mut ctx, tz, output, &filename_out)? {
+            info!("No changes in {filename_out}");
+        }
+    }
+    info!(
+        "Template rendering took {} ms.",
+        Utc::now().signed_duration_since(now).num_milliseconds()
+    );
+
+    for export in &cfg.export {
+        let filename_out = format!("{html_dir}/{}", export.file);
+        let rows = match export.uniq {
+            false => &db_data,
+            true => &db_data_uniq,
+        };
+        if !write_export(export.format, rows, ts_limit, output, &filename_out)? {
+            info!("No changes in {filename_out}");
+        }
+    }
+    Ok(())
+}
+
+fn template_tz<'a>(cfg: &'a ConfigCommon, name: &str) -> &'a Tz {
+    match cfg.template_tz.as_ref() {
+        Some(map) => get_wild(map, name).unwrap_or(&Tz::UTC),
+        None => &Tz::UTC,
+    }
+}
+
+// Returns false if the page content did not change and nothing was written
+fn render_page<P: AsRef<path::Path>>(
+    tera: &Tera,
+    template: &str,
+    ctx: &mut tera::Context,
+    tz: &Tz,
+    output: &mut OutputWriter,
+    filename_out: P,
+) -> anyhow::Result<bool> {
+    let _timer = metrics().render_seconds.with_label_values(&[template]).start_timer();
+    // the page update time alone does not count as a change, it goes in after the fingerprint
+    ctx.insert("last_change", LAST_CHANGE_PLACEHOLDER);
+    let page = tera.render(template, ctx)?;
+    let fingerprint = content_etag(page.as_bytes());
+    if output.is_current(&filename_out, &fingerprint) {
+        return Ok(false);
+    }
+
+    let page = page.replace(LAST_CHANGE_PLACEHOLDER, &Utc::now().timestamp().ts_long_tz(tz));
+    output.write(&filename_out, page.as_bytes())?;
+    output.set_fingerprint(&filename_out, fingerprint);
+    Ok(true)
+}
+
+// Export schema version 1, see README.md before changing anything here
+#[derive(Debug, Serialize)]
+struct ExportRow<'a> {
+    id: i32,
+    seen_first: i64,
+    seen_last: i64,
+    seen_count: i64,
+    channels: Vec<&'a str>,
+    nicks: Vec<&'a str>,
+    url: &'a str,
+    title: &'a str,
+}
+
+impl<'a> From<&'a DbUrlRow> for ExportRow<'a> {
+    fn from(row: &'a DbUrlRow) -> Self {
+        let sorted = |s: &'a str| s.split_whitespace().sorted_unstable().dedup().collect::<Vec<_>>();
+        Self {
+            id: row.id,
+            seen_first: row.seen_first,
+            seen_last: row.seen_last,
+            seen_count: row.seen_cnt,
+            channels: sorted(&row.channel),
+            nicks: sorted(&row.nick),
+            url: &row.url,
+            title: &row.title,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ExportDoc<'a> {
+    schema: &'a str,
+    version: u32,
+    generated: i64,
+    since: i64,
+    rows: &'a [ExportRow<'a>],
+}
+
+// Returns false if the exported rows did not change and nothing was written
+fn write_export<P: AsRef<path::Path>>(
+    format: ExportFormat,
+    rows: &[DbUrlRow],
+    since: i64,
+    output: &mut OutputWriter,
+    filename_out: P,
+) -> anyhow::Result<bool> {
+    let rows = rows.iter().map(ExportRow::from).collect::<Vec<_>>();
+    // the generation time alone does not count as a change
+    let fingerprint = content_etag(&serde_json::to_vec(&rows)?);
+    if output.is_current(&filename_out, &fingerprint) {
+        return Ok(false);
+    }
+
+    output.write(&filename_out, &export_content(format, &rows, since)?)?;
+    output.set_fingerprint(&filename_out, fingerprint);
+    Ok(true)
+}
+
+fn export_content(format: ExportFormat, rows: &[ExportRow], since: i64) -> anyhow::Result<Vec<u8>> {
+    Ok(match format {
+        ExportFormat::Json => serde_json::to_vec_pretty(&ExportDoc {
+            schema: EXPORT_SCHEMA,
+            version: EXPORT_VERSION,
+            generated: Utc::now().timestamp(),
+            since,
+            rows,
+        })?,
+        ExportFormat::Ndjson => {
+            let mut buf = Vec::with_capacity(rows.len() * 256);
+            for row in rows {
+                serde_json::to_writer(&mut buf, row)?;
+                buf.push(b'\n');
+            }
+            buf
+        }
+        ExportFormat::Csv => {
+            let mut wr = csv::Writer::from_writer(Vec::with_capacity(rows.len() * 256));
+            wr.write_record(EXPORT_CSV_HEADER)?;
+            for row in rows {
+                wr.write_record([
+                    row.id.to_string(),
+                    row.seen_first.to_string(),
+                    row.seen_last.to_string(),
+                    row.seen_count.to_string(),
+                    row.channels.join(" "),
+                    row.nicks.join(" "),
+                    row.url.to_string(),
+                    row.title.to_string(),
+                ])?;
+            }
+            wr.into_inner()?
+        }
+    })
+}
+
+const CTX_NUM: usize = 32;
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Eq, Hash, Sequence, PartialEq)]
+enum CtxData {
+    id,
+    seen_first,
+    seen_last,
+    seen_cnt,
+    channel,
+    nick,
+    url,
+    title,
+    msg,
+    kind,
+    uniq_id,
+    uniq_seen_first,
+    uniq_seen_last,
+    uniq_seen_cnt,
+    uniq_channel,
+    uniq_nick,
+    uniq_url,
+    uniq_title,
+    uniq_msg,
+    uniq_kind,
+    // unformatted timestamps for the ts_format and ts_relative filters
+    seen_first_ts,
+    seen_last_ts,
+    uniq_seen_first_ts,
+    uniq_seen_last_ts,
+}
+
+// with this we get to_string() for free
+impl fmt::Display for CtxData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&format!("{self:?}"))
+    }
+}
+
+async fn read_db<R: UrlRepo>(repo: &R, ts_limit: i64) -> anyhow::Result<(Vec<DbUrlRow>, Vec<DbUrlRow>)> {
+    let db_data = repo.recent(ts_limit, UrlGrouping::PerChannel).await?;
+    info!("Got {} rows.", db_data.len());
+
+    let db_data_uniq = repo.recent(ts_limit, UrlGrouping::Uniq).await?;
+    info!("Got {} uniq rows.", db_data_uniq.len());
+    Ok((db_data, db_data_uniq))
+}
+
+async fn generate_ctx(db_data: &[DbUrlRow], db_data_uniq: &[DbUrlRow], tz: &Tz) -> anyhow::Result<tera::Context> {
+    let mut data: HashMap<CtxData, Vec<String>> = HashMap::with_capacity(CTX_NUM);
+    // Magic to iterate through all enum variants
+    for k in enum_iterator::all::<CtxData>() {
+        let v: Vec<String> = Vec::with_capacity(VEC_SZ);
+        data.insert(k, v);
+    }
+
+    let mut ctx = tera::Context::new();
+    ctx.insert("last_change", &Utc::now().timestamp().ts_long_tz(tz));
+
+    let mut n_rows: usize = 0;
+    for row in db_data {
+        data.get_mut(&CtxData::id)
+            .ok_or_else(|| anyhow!("no data"))?
+            .push(row.id.to_string());
+        data.get_mut(&CtxData::seen_first)
+            .ok_or_else(|| anyhow!("no data"))?
+            .push(row.seen_first.ts_short_y_tz(tz));
+        data.get_mut(&CtxData::seen_last)
+            .ok_or_else(|| anyhow!("no data"))?
+            .push(row.seen_last.ts_short_tz(tz));
+        data.get_mut(&CtxData::seen_first_ts)
+            .ok_or_else(|| anyhow!("no data"))?
+            .push(row.seen_first.to_string());
+        data.get_mut(&CtxData::seen_last_ts)
+            .ok_or_else(|| anyhow!("no data"))?
+            .push(row.seen_last.to_string());
+        data.get_mut(&CtxData::seen_cnt)
+            .ok_or_else(|| anyhow!("no data"))?
+            .push(row.seen_cnt.to_string());
+        data.get_mut(&CtxData::channel)
+            .ok_or_else(|| anyhow!("no data"))?
+            .push(row.channel.clone().esc_et_lt_gt());
+        data.get_mut(&CtxData::nick)
+            .ok_or_else(|| anyhow!("no data"))?
+            .push(row.nick.clone().esc_et_lt_gt());
+        data.get_mut(&CtxData::url)
+            .ok_or_else(|| anyhow!("no data"))?
+            .push(row.url.clone().esc_quot());
+        data.get_mut(&CtxData::title)
+            .ok_or_else(|| anyhow!("no data"))?
+            .push(row.title.clone().esc_et_lt_gt());
+        data.get_mut(&CtxData::msg)
+            .ok_or_else(|| anyhow!("no data"))?
+            .push(row.msg.clone().esc_et_lt_gt());
+        data.get_mut(&CtxData::kind)
+            .ok_or_else(|| anyhow!("no data"))?
+            .push(row.kind.clone());
+        n_rows += 1;
+    }
+    info!("Got {n_rows} rows.");
+    ctx.insert("n_rows", &n_rows);
+
+    n_rows = 0;
+    for row in db_data_uniq {
+        data.get_mut(&CtxData::uniq_id)
+            .ok_or_else(|| anyhow!("no data"))?
+            .push(row.id.to_string());
+        data.get_mut(&CtxData::uniq_seen_first)
+            .ok_or_else(|| anyhow!("no data"))?
+            .push(row.seen_first.ts_short_y_tz(tz));
+        data.get_mut(&CtxData::uniq_seen_last)
+            .ok_or_else(|| anyhow!("no data"))?
+            .push(row.seen_last.ts_short_tz(tz));
+        data.get_mut(&CtxData::uniq_seen_first_ts)
+            .ok_or_else(|| anyhow!("no data"))?
+            .push(row.seen_first.to_string());
+        data.get_mut(&CtxData::uniq_seen_last_ts)
+            .ok_or_else(|| anyhow!("no data"))?
+            .push(row.seen_last.to_string());
+        data.get_mut(&CtxData::uniq_seen_cnt)
+            .ok_or_else(|| anyhow!("no data"))?
+            .push(row.seen_cnt.to_string());
+        data.get_mut(&CtxData::uniq_channel)
+            .ok_or_else(|| anyhow!("no data"))?
+            .push(row.channel.clone().esc_et_lt_gt().sort_dedup_br());
+        data.get_mut(&CtxData::uniq_nick)
+            .ok_or_else(|| anyhow!("no data"))?
+            .push(row.nick.clone().esc_et_lt_gt().sort_dedup_br());
+        data.get_mut(&CtxData::uniq_url)
+            .ok_or_else(|| anyhow!("no data"))?
+            .push(row.url.clone().esc_quot());
+        data.get_mut(&CtxData::uniq_title)
+            .ok_or_else(|| anyhow!("no data"))?
+            .push(row.title.clone().esc_et_lt_gt());
+        data.get_mut(&CtxData::uniq_msg)
+            .ok_or_else(|| anyhow!("no data"))?
+            .push(row.msg.clone().esc_et_lt_gt());
+        data.get_mut(&CtxData::uniq_kind)
+            .ok_or_else(|| anyhow!("no data"))?
+            .push(row.kind.clone());
+        n_rows += 1;
+    }
+    info!("Got {n_rows} uniq rows.");
+    ctx.insert("uniq_n_rows", &n_rows);
+
+    for k in enum_iterator::all::<CtxData>() {
+        let k_name = k.to_string();
+        ctx.insert(
+            k_name.clone(),
+            data.get(&k).ok_or_else(|| anyhow!("No data for {k_name}"))?,
+        );
+    }
+
+    Ok(ctx)
+}
+
+struct Templates {
+    pages: Tera,
+    archive: Option<ArchiveTemplates>,
+}
+
+struct ArchiveTemplates {
+    tera: Tera,
+    // of the template sources, the archived months are rendered again when it changes
+    fingerprint: String,
+}
+
+// Loads and trial renders the templates like a start would, for the config check
+pub async fn check_templates(cfg: &ConfigCommon) -> anyhow::Result<()> {
+    load_templates(cfg).await.map(|_| ())
+}
+
+async fn load_templates(cfg: &ConfigCommon) -> anyhow::Result<Templates> {
+    let tera_dir = &cfg.template_dir;
+    info!("Template directory: {tera_dir}");
+    let mut tera = Tera::new();
+    register_tera_extras(&mut tera);
+    if let Err(e) = tera.load_from_glob(&format!("{tera_dir}/*.tera")) {
+        return Err(anyhow!("Tera template parsing error: {e:?}"));
+    }
+    if tera.get_template_names().count() < 1 {
+        info!("No templates found, using the built-in defaults");
+        if let Err(e) = tera.add_raw_templates(DEFAULT_TERA_TEMPLATES.iter().copied()) {
+            return Err(anyhow!("Tera default template parsing error: {e:?}"));
+        }
+    }
+    info!(
+        "Found templates: [{}]",
+        tera.get_template_names().collect::<Vec<_>>().join(", ")
+    );
+
+    let archive = match cfg.archive_enabled {
+        false => None,
+        true => Some(load_archive_templates(tera_dir)?),
+    };
+
+    let templates = Templates { pages: tera, archive };
+    validate_templates(&templates).await?;
+    Ok(templates)
+}
+
+// Trial render everything against sample data, so that broken templates are caught before use
+async fn validate_templates(templates: &Templates) -> anyhow::Result<()> {
+    let rows = sample_rows();
+    let mut ctx = generate_ctx(&rows, &rows, &Tz::UTC).await?;
+    for template in templates.pages.get_template_names() {
+        if let Err(e) = templates.pages.render(template, &ctx) {
+            bail!("Tera template {template} render error: {e:?}");
+        }
+    }
+
+    if let Some(archive) = templates.archive.as_ref().map(|a| &a.tera) {
+        let channels = vec![archive_channel("1970-01", "#42")];
+        ctx.insert("root", "../");
+        ctx.insert("archive_month", "1970-01");
+        ctx.insert("archive_channel", "");
+        ctx.insert("archive_channels", &channels);
+        if let Err(e) = archive.render(ARCHIVE_TPL_MONTH, &ctx) {
+            bail!("Tera archive template {ARCHIVE_TPL_MONTH} render error: {e:?}");
+        }
+
+        let mut ctx = tera::Context::new();
+        ctx.insert("last_change", "");
+        ctx.insert("root", "../");
+        ctx.insert(
+            "archive_months",
+            &vec![ArchiveMonth {
+                month: "1970-01".to_owned(),
+                n_url: 1,
+                channels,
+            }],
+        );
+        if let Err(e) = archive.render(ARCHIVE_TPL_INDEX, &ctx) {
+            bail!("Tera archive template {ARCHIVE_TPL_INDEX} render error: {e:?}");
+        }
+    }
+    Ok(())
+}
+
+fn sample_rows() -> Vec<DbUrlRow> {
+    vec![DbUrlRow {
+        id: 1,
+        seen_first: 1,
+        seen_last: 2,
+        seen_cnt: 2,
+        channel: "#42".to_owned(),
+        nick: "test".to_owned(),
+        url: "https://example.com".to_owned(),
+        title: "Example".to_owned(),
+        msg: "look at https://example.com".to_owned(),
+        kind: "privmsg".to_owned(),
+    }]
+}
+
+fn load_archive_templates(tera_dir: &str) -> anyhow::Result<ArchiveTemplates> {
+    let archive_dir = path::Path::new(tera_dir).join(ARCHIVE_SUBDIR);
+    let mut tera = Tera::new();
+    register_tera_extras(&mut tera);
+    if let Err(e) = tera.load_from_glob(&format!("{}/*.tera", archive_dir.display())) {
+        return Err(anyhow!("Tera archive template parsing error: {e:?}"));
+    }
+    let mut files = fs::read_dir(&archive_dir)
+        .map(|dir| {
+            dir.filter_map(|e| e.ok().map(|e| e.path()))
+                .filter(|p| p.extension().is_some_and(|ext| ext == "tera"))
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    files.sort();
+    let mut sources = Vec::new();
+    for file in files {
+        sources.extend(file.to_string_lossy().as_bytes());
+        sources.extend(fs::read(&file)?);
+    }
+
+    for (template, content) in DEFAULT_ARCHIVE_TEMPLATES {
+        if !tera.contains_template(template) {
+            info!("Archive template {tera_dir}/{ARCHIVE_SUBDIR}/{template} not found, using the built-in default");
+            if let Err(e) = tera.add_raw_template(template, content) {
+                return Err(anyhow!("Tera default archive template parsing error: {e:?}"));
+            }
+            sources.extend(template.as_bytes());
+            sources.extend(content.as_bytes());
+        }
+    }
+    Ok(ArchiveTemplates {
+        tera,
+        fingerprint: content_etag(&sources),
+    })
+}
+
+// Cheap summary of a month in the database, used to detect which months changed.
+// Deletes change the counts, new sightings and refreshed metadata change the max ids.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct MonthStat {
+    n_url: i64,
+    max_id: i32,
+    n_meta: i64,
+    max_meta_id: i32,
+    #[serde(default)]
+    n_topic: i64,
+}
+
+impl From<DbStats> for MonthStat {
+    fn from(s: DbStats) -> Self {
+        Self {
+            n_url: s.n_url,
+            max_id: s.max_id,
+            n_meta: s.n_meta,
+            max_meta_id: s.max_meta_id,
+            n_topic: s.n_topic,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct MonthState {
+    stat: MonthStat,
+    channels: Vec<String>,
+    // channel_visibility and nick_optout the pages were generated with
+    #[serde(default)]
+    privacy: String,
+    // whether the month has per-channel pages, and their file naming
+    #[serde(default)]
+    per_channel: Option<u32>,
+    // the archive template fingerprint
+    #[serde(default)]
+    templates: String,
+    // the template_timezone zone of the archive
+    #[serde(default)]
+    timezone: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ArchiveChannel {
+    channel: String,
+    file: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ArchiveTopic {
+    seen: String,
+    nick: String,
+    topic: String,
+}
+
+impl ArchiveTopic {
+    fn new(t: &DbTopic, tz: &Tz) -> Self {
+        Self {
+            seen: t.seen.ts_short_y_tz(tz),
+            nick: t.nick.esc_et_lt_gt(),
+            topic: t.topic.esc_et_lt_gt(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ArchiveMonth {
+    month: String,
+    n_url: i64,
+    channels: Vec<ArchiveChannel>,
+}
+
+async fn generate_archive<R: UrlRepo>(
+    repo: &R,
+    archive: &ArchiveTemplates,
+    cfg: &ConfigCommon,
+    output: &mut OutputWriter,
+) -> anyhow::Result<()> {
+    let tera = &archive.tera;
+    let now = Utc::now();
+    let tz = template_tz(cfg, ARCHIVE_TZ_KEY);
+    let archive_dir = path::Path::new(&cfg.html_dir).join(ARCHIVE_SUBDIR);
+    fs::create_dir_all(&archive_dir)?;
+
+    let state_file = archive_dir.join(ARCHIVE_STATE);
+    let mut state: HashMap<String, MonthState> = match fs::File::open(&state_file) {
+        Ok(f) => serde_json::from_reader(io::BufReader::new(f)).unwrap_or_else(|e| {
+            warn!("Ignoring unreadable archive state {state_file:?}: {e}");
+            HashMap::new()
+        }),
+        Err(_) => HashMap::new(),
+    };
+
+    let all = repo.stats(i64::MIN, i64::MAX).await?;
+    let months = match all.n_url {
+        0 => Vec::new(),
+        _ => archive_months(all.seen_first, all.seen_last, tz),
+    };
+
+    let privacy = cfg.privacy().fingerprint();
+    let per_channel = cfg.archive_per_channel.then_some(ARCHIVE_FILE_NAMING);
+    let mut n_changed: usize = 0;
+    let mut index = Vec::with_capacity(months.len());
+    let mut months_shown = HashSet::with_capacity(months.len());
+    for (month, ts_start, ts_end) in months {
+        health().beat();
+        let stat = MonthStat::from(repo.stats(ts_start, ts_end).await?);
+        if stat.n_url == 0 {
+            continue;
+        }
+        months_shown.insert(month.clone());
+
+        let month_file = archive_dir.join(format!("{month}.html"));
+        let unchanged = state.get(&month).is_some_and(|s| {
+            s.stat == stat
+                && s.privacy == privacy
+                && s.per_channel == per_channel
+                && s.templates == archive.fingerprint
+                && s.timezone == tz.name()
+        }) && month_file.exists();
+        if !unchanged {
+            info!("Generating archive month {month}");
+            let channels =
+                generate_archive_month(repo, tera, cfg, output, tz, &archive_dir, &month, ts_start, ts_end).await?;
+            // e.g. a channel that was hidden since, or all of them with archive_per_channel turned off
+            if let Some(old) = state.get(&month) {
+                for chan in old
+                    .channels
+                    .iter()
+                    .filter(|c| per_channel.is_none() || !channels.contains(c))
+                {
+                    output.remove(archive_dir.join(&month).join(format!("{}.html", chan.safe_filename())))?;
+                }
+            }
+            state.insert(
+                month.clone(),
+                MonthState {
+                    stat: stat.clone(),
+                    channels,
+                    privacy: privacy.clone(),
+                    per_channel,
+                    templates: archive.fingerprint.clone(),
+                    timezone: tz.name().to_owned(),
+                },
+            );
+            n_changed += 1;
+        }
+
+        let channels = match cfg.archive_per_channel {
+            false => Vec::new(),
+            true => state
+                .get(&month)
+                .map(|s| s.channels.clone())
+                .unwrap_or_default()
+                .iter()
+                .map(|c| archive_channel(&month, c))
+                .collect(),
+        };
+        index.push(ArchiveMonth {
+            month,
+            n_url: stat.n_url,
+            channels,
+        });
+    }
+
+    // months with nothing left to show
+    let gone = state
+        .keys()
+        .filter(|m| !months_shown.contains(*m))
+        .cloned()
+        .collect::<Vec<_>>();
+    for month in gone {
+        if let Some(old) = state.remove(&month) {
+            for chan in &old.channels {
+                output.remove(archive_dir.join(&month).join(format!("{}.html", chan.safe_filename())))?;
+            }
+        }
+        output.remove(archive_dir.join(format!("{month}.html")))?;
+        n_changed += 1;
+    }
+
+    let index_file = archive_dir.join("index.html");
+    if n_changed > 0 || !index_file.exists() {
+        index.reverse();
+        let mut ctx = tera::Context::new();
+        ctx.insert("root", "../");
+        ctx.insert("archive_months", &index);
+        render_page(tera, ARCHIVE_TPL_INDEX, &mut ctx, tz, output, &index_file)?;
+        write_atomic(&state_file, serde_json::to_string_pretty(&state)?.as_bytes())?;
+    }
+    info!(
+        "Archive: {n_changed} month(s) regenerated in {} ms.",
+        Utc::now().signed_duration_since(now).num_milliseconds()
+    );
+    Ok(())
+}
+
+// Returns the channels seen during the month
+#[allow(clippy::too_many_arguments)]
+async fn generate_archive_month<R: UrlRepo>(
+    repo: &R,
+    tera: &Tera,
+    cfg: &ConfigCommon,
+    output: &mut OutputWriter,
+    tz: &Tz,
+    archive_dir: &path::Path,
+    month: &str,
+    ts_start: i64,
+    ts_end: i64,
+) -> anyhow::Result<Vec<String>> {
+    let db_data = repo.range(ts_start, ts_end, UrlGrouping::PerChannel).await?;
+    let db_data_uniq = repo.range(ts_start, ts_end, UrlGrouping::Uniq).await?;
+    let channels = db_data
+        .iter()
+        .map(|row| row.channel.clone())
+        .collect::<std::collections::BTreeSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+    let channel_links = match cfg.archive_per_channel {
+        false => Vec::new(),
+        true => channels.iter().map(|c| archive_channel(month, c)).collect(),
+    };
+
+    let mut ctx = generate_ctx(&db_data, &db_data_uniq, tz).await?;
+    ctx.insert("root", "../");
+    ctx.insert("archive_month", month);
+    ctx.insert("archive_channel", "");
+    ctx.insert("archive_channels", &channel_links);
+    ctx.insert("archive_topics", &Vec::<ArchiveTopic>::new());
+    render_page(
+        tera,
+        ARCHIVE_TPL_MONTH,
+        &mut ctx,
+        tz,
+        output,
+        archive_dir.join(format!("{month}.html")),
+    )?;
+
+    if cfg.archive_per_channel {
+        let month_dir = archive_dir.join(month);
+        fs::create_dir_all(&month_dir)?;
+        for channel in &channels {
+            let chan_data = db_data
+                .iter()
+                .filter(|row| &row.channel == channel)
+                .cloned()
+                .collect::<Vec<_>>();
+            let mut ctx = generate_ctx(&chan_data, &chan_data, tz).await?;
+            ctx.insert("root", "../../");
+            ctx.insert("archive_month", month);
+            ctx.insert("archive_channel", &channel.as_str().esc_et_lt_gt());
+            ctx.insert("archive_channels", &channel_links);
+            let topics = repo.topics(channel, ts_start, ts_end).await?;
+            ctx.insert(
+                "archive_topics",
+                &topics.iter().map(|t| ArchiveTopic::new(t, tz)).collect::<Vec<_>>(),
+            );
+            render_page(
+                tera,
+                ARCHIVE_TPL_MONTH,
+                &mut ctx,
+                tz,
+                output,
+                month_dir.join(format!("{}.html", channel.as_str().safe_filename())),
+            )?;
+        }
+    }
+    Ok(channels)
+}
+
+fn archive_channel(month: &str, channel: &str) -> ArchiveChannel {
+    ArchiveChannel {
+        channel: channel.esc_et_lt_gt(),
+        file: format!("{month}/{}.html", channel.safe_filename()),
+    }
+}
+
+// List the months covering the given timestamps as ("YYYY-MM", start, end) in the given timezone
+fn archive_months(ts_first: i64, ts_last: i64, tz: &Tz) -> Vec<(String, i64, i64)> {
+    let (Some(first), Some(last)) = (
+        DateTime::from_timestamp(ts_first, 0),
+        DateTime::from_timestamp(ts_last, 0),
+    ) else {
+        return Vec::new();
+    };
+    let (first, last) = (first.with_timezone(tz), last.with_timezone(tz));
+
+    let mut months = Vec::new();
+    let (mut year, mut mon) = (first.year(), first.month());
+    while (year, mon) <= (last.year(), last.month()) {
+        let (next_year, next_mon) = if mon == 12 { (year + 1, 1) } else { (year, mon + 1) };
+        months.push((
+            format!("{year:04}-{mon:02}"),
+            month_start(tz, year, mon),
+            month_start(tz, next_year, next_mon),
+        ));
+        (year, mon) = (next_year, next_mon);
+    }
+    months
+}
+
+fn month_start(tz: &Tz, year: i32, mon: u32) -> i64 {
+    NaiveDate::from_ymd_opt(year, mon, 1)
+        .and_then(|d| d.and_hms_opt(0, 0, 0))
+        .map_or(0, |naive| {
+            // midnight may fall into a DST gap
+            tz.from_local_datetime(&naive)
+                .earliest()
+                .unwrap_or_else(|| tz.from_utc_datetime(&naive))
+                .timestamp()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Besides the Tera builtins, templates can use these project specific extras:
+    //
+    // - `ts | ts_format(fmt="%b %d %H:%M", tz="EET")` formats a unix timestamp,
+    //   `fmt` defaults to "%Y-%m-%d %H:%M:%S" and `tz` to UTC
+    // - `ts | ts_relative` gives e.g. "3 hours ago", `now=<ts>` overrides the current time
+    // - `url | domain` gives the host name without a leading "www."
+    // - `url | favicon_url` gives the conventional /favicon.ico location of the site
+    // - `title | truncate_chars(length=80, end="...")` cuts on a char boundary
+    //   and never in the middle of an html entity
+    // - `channel | channel_colour` gives a stable css hsl() colour,
+    //   `saturation` and `lightness` percentages can be given
+    // - `now_ts()` gives the current unix timestamp
+    //
+    // The raw timestamps are in the `seen_first_ts`, `seen_last_ts`,
+    // `uniq_seen_first_ts` and `uniq_seen_last_ts` arrays.
+    #[tokio::test]
+    async fn renders_checked_in_templates() {
+        let template_dir = concat!(env!("CARGO_MANIFEST_DIR"), "/templates");
+        let mut tera = Tera::new();
+        register_tera_extras(&mut tera);
+        tera.load_from_glob(&format!("{template_dir}/*.tera"))
+            .expect("checked-in Tera templates should parse");
+        // a row for each channel the checked-in per-channel templates pick
+        let channels = [
+            "#42",
+            "#blerp",
+            "#ham.fi",
+            "slack-Kädenvääntö-random",
+            "#networker",
+            "#paskat_urlit",
+            "#saab",
+        ];
+        let rows = channels
+            .iter()
+            .map(|chan| DbUrlRow {
+                channel: chan.to_string(),
+                msg: format!("look at this <{chan}>"),
+                ..sample_rows().remove(0)
+            })
+            .collect::<Vec<_>>();
+        let unique_rows = sample_rows();
+        let context = generate_ctx(&rows, &unique_rows, &Tz::UTC)
+            .await
+            .expect("representative template context should build");
+
+        for template in tera.get_template_names() {
+            let page = tera
+                .render(template, &context)
+                .unwrap_or_else(|e| panic!("checked-in Tera template {template} should render: {e}"));
+            // the message and its kind, escaped
+            assert!(page.contains("privmsg: look at "), "{template} shows no message");
+            assert!(
+                !page.contains("look at this <"),
+                "{template} shows an unescaped message"
+            );
+        }
+
+        let extras = [
+            (
+                r#"{{ uniq_seen_last_ts[0] | ts_format(fmt="%Y-%m-%d %H:%M", tz="EET") }}"#,
+                "1970-01-01 02:00",
+            ),
+            (r#"{{ uniq_seen_first_ts[0] | ts_relative(now=7201) }}"#, "2 hours ago"),
+            (r#"{{ "https://www.example.com/x" | domain }}"#, "example.com"),
+            (r#"{{ uniq_url[0] | favicon_url }}"#, "https://example.com/favicon.ico"),
+            (r#"{{ "Fish &amp; Chips" | truncate_chars(length=7) }}"#, "Fish ..."),
+            (r#"{{ uniq_channel[0] | channel_colour }}"#, "hsl(208, 55%, 45%)"),
+        ];
+        for (input, expected) in extras {
+            let output = tera
+                .render_str(input, &context, false)
+                .unwrap_or_else(|e| panic!("template extra {input} should render: {e}"));
+            assert_eq!(output, expected, "{input}");
+        }
+        assert!(tera.render_str("{{ now_ts() }}", &context, false).is_ok());
+        // a bad format fails the render instead of panicking
+        let bad_fmt = tera.render_str(r#"{{ 1 | ts_format(fmt="%Y %Q") }}"#, &context, false);
+        assert!(bad_fmt.is_err_and(|e| format!("{e:?}").contains("%Y %Q")));
+    }
+
+    #[test]
+    fn renders_each_page_once() {
+        let html_dir = std::env::temp_dir().join(format!("urlharvest-render-test.{}", std::process::id()));
+        fs::create_dir_all(&html_dir).unwrap();
+        let page = html_dir.join("page.html");
+        let mut tera = Tera::new();
+        tera.add_raw_template("page.html", "<p>{{ n }}</p><p>{{ last_change }}</p>")
+            .unwrap();
+        let mut output = OutputWriter::new(&test_config(serde_json::json!({
+            "html_dir": html_dir.to_string_lossy()
+        })));
+        let mut ctx = tera::Context::new();
+        ctx.insert("n", &1);
+
+        assert!(render_page(&tera, "page.html", &mut ctx, &Tz::UTC, &mut output, &page).unwrap());
+        let written = fs::read_to_string(&page).unwrap();
+        assert!(!written.contains(LAST_CHANGE_PLACEHOLDER) && written.contains(&Utc::now().year().to_string()));
+        // only the update time would differ
+        assert!(!render_page(&tera, "page.html", &mut ctx, &Tz::UTC, &mut output, &page).unwrap());
+        ctx.insert("n", &2);
+        assert!(render_page(&tera, "page.html", &mut ctx, &Tz::UTC, &mut output, &page).unwrap());
+        let _ = fs::remove_dir_all(&html_dir);
+    }
+
+    #[tokio::test]
+    async fn renders_checked_in_archive_templates() {
+        let template_dir = concat!(env!("CARGO_MANIFEST_DIR"), "/templates");
+        let tera = load_archive_templates(template_dir)
+            .expect("checked-in archive templates should load")
+            .tera;
+
+        let mut context = generate_ctx(&sample_rows(), &sample_rows(), &Tz::UTC)
+            .await
+            .expect("representative template context should build");
+        let channels = vec![archive_channel("1970-01", "#42")];
+        context.insert("root", "../");
+        context.insert("archive_month", "1970-01");
+        context.insert("archive_channel", "#42");
+        context.insert("archive_channels", &channels);
+        let month = tera
+            .render(ARCHIVE_TPL_MONTH, &context)
+            .expect("archive month template should render");
+        assert!(month.contains(&format!("1970-01/{}.html", "#42".safe_filename())));
+
+        let mut context = tera::Context::new();
+        context.insert("last_change", "now");
+        context.insert("root", "../");
+        context.insert(
+            "archive_months",
+            &vec![ArchiveMonth {
+                month: "1970-01".to_owned(),
+                n_url: 1,
+                channels,
+            }],
+        );
+        let index = tera
+            .render(ARCHIVE_TPL_INDEX, &context)
+            .expect("archive index template should render");
+        assert!(index.contains("1970-01.html"));
+    }
+
+    #[test]
+    fn built_in_defaults_load() {
+        let mut tera = Tera::new();
+        register_tera_extras(&mut tera);
+        tera.add_raw_templates(DEFAULT_TERA_TEMPLATES.iter().copied())
+            .expect("built-in templates should parse");
+
+        let tera = load_archive_templates("/nonexistent")
+            .expect("built-in archive templates should be used")
+            .tera;
+        assert!(tera.contains_template(ARCHIVE_TPL_MONTH));
+        assert!(tera.contains_template(ARCHIVE_TPL_INDEX));
+    }
+
+    #[test]
+    fn export_schema_v1() {
+        let data = sample_rows();
+        let rows = data.iter().map(ExportRow::from).collect::<Vec<_>>();
+
+        let json: serde_json::Value =
+            serde_json::from_slice(&export_content(ExportFormat::Json, &rows, 0).unwrap()).unwrap();
+        assert_eq!(json["schema"], EXPORT_SCHEMA);
+        assert_eq!(json["version"], 1);
+        assert_eq!(
+            json["rows"][0],
+            serde_json::json!({
+                "id": 1,
+                "seen_first": 1,
+                "seen_last": 2,
+                "seen_count": 2,
+                "channels": ["#42"],
+                "nicks": ["test"],
+                "url": "https://example.com",
+                "title": "Example",
+            })
+        );
+
+        let ndjson = export_content(ExportFormat::Ndjson, &rows, 0).unwrap();
+        let line: serde_json::Value = serde_json::from_slice(ndjson.split(|b| *b == b'\n').next().unwrap()).unwrap();
+        assert_eq!(line, json["rows"][0]);
+
+        let csv = String::from_utf8(export_content(ExportFormat::Csv, &rows, 0).unwrap()).unwrap();
+        assert_eq!(
+            csv,
+            "id,seen_first,seen_last,seen_count,channels,nicks,url,title\n\
+            1,1,2,2,#42,test,https://example.com,Example\n"
+        );
+    }
+
+    #[test]
+    fn archive_months_follow_timezone() {
+        let tz: Tz = "Europe/Helsinki".parse().unwrap();
+        // 2023-12-31 23:30 UTC is already January in Helsinki
+        let months = archive_months(1704065400, 1706738399, &tz);
+        let names = months.iter().map(|m| m.0.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["2024-01"]);
+        assert_eq!(months[0].1, 1704060000);
+        assert_eq!(months[0].2, 1706738400);
+    }
+
+    #[tokio::test]
+    async fn archive_follows_per_channel_setting() {
+        let html_dir = std::env::temp_dir().join(format!("urlharvest-archive-test.{}", std::process::id()));
+        let mut cfg = test_config(serde_json::json!({
+            "html_dir": html_dir.to_string_lossy()
+        }));
+        let repo = MemRepo::new();
+        let sighting = |ts, chan| test_sighting(ts, chan, "a", &format!("https://{ts}.example"));
+        repo.add_sightings(&[sighting(100, "#a.b"), sighting(200, "&a_b")])
+            .await
+            .unwrap();
+        add_test_meta(&repo, 1..=2).await;
+        let mut archive = load_archive_templates("/nonexistent").unwrap();
+        let archive_dir = html_dir.join(ARCHIVE_SUBDIR);
+        let chan_files = ["#a.b", "&a_b"].map(|c| archive_dir.join(format!("1970-01/{}.html", c.safe_filename())));
+        assert_ne!(chan_files[0], chan_files[1]);
+
+        let mut output = OutputWriter::new(&cfg);
+        generate_archive(&repo, &archive, &cfg, &mut output).await.unwrap();
+        assert!(chan_files.iter().all(|f| !f.exists()));
+
+        // the months already archived get their channel pages and links too
+        cfg.archive_per_channel = true;
+        generate_archive(&repo, &archive, &cfg, &mut output).await.unwrap();
+        assert!(chan_files.iter().all(|f| f.exists()));
+        let index = fs::read_to_string(archive_dir.join("index.html")).unwrap();
+        let month = fs::read_to_string(archive_dir.join("1970-01.html")).unwrap();
+        for chan in ["#a.b", "&a_b"] {
+            let link = format!("1970-01/{}.html", chan.safe_filename());
+            assert!(index.contains(&link) && month.contains(&link), "{link}");
+        }
+
+        // e.g. edited templates taken into use with a SIGHUP
+        archive.fingerprint = "changed".to_owned();
+        generate_archive(&repo, &archive, &cfg, &mut output).await.unwrap();
+        let state: HashMap<String, MonthState> =
+            serde_json::from_slice(&fs::read(archive_dir.join(ARCHIVE_STATE)).unwrap()).unwrap();
+        assert_eq!(state["1970-01"].templates, "changed");
+
+        // and a changed template_timezone
+        cfg.template_tz = Some(HashMap::from([("*".to_owned(), Tz::Europe__Helsinki)]));
+        let month_file = archive_dir.join("1970-01.html");
+        fs::write(&month_file, "stale").unwrap();
+        generate_archive(&repo, &archive, &cfg, &mut output).await.unwrap();
+        assert_ne!(fs::read_to_string(&month_file).unwrap(), "stale");
+        let state: HashMap<String, MonthState> =
+            serde_json::from_slice(&fs::read(archive_dir.join(ARCHIVE_STATE)).unwrap()).unwrap();
+        assert_eq!(state["1970-01"].timezone, "Europe/Helsinki");
+
+        cfg.archive_per_channel = false;
+        generate_archive(&repo, &archive, &cfg, &mut output).await.unwrap();
+        assert!(chan_files.iter().all(|f| !f.exists()));
+        let _ = fs::remove_dir_all(&html_dir);
+    }
+}
+// EOF
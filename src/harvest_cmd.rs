@@ -0,0 +1,388 @@
+// harvest_cmd.rs
+
+use linemux::MuxedLines;
+
+use crate::*;
+
+// the status_listen key
+pub const DAEMON: &str = "irssi_urlharvest";
+// defaults for the [harvest] settings, in seconds
+const PROGRESS_INTERVAL: u64 = 5;
+const CLOCK_SLACK: u64 = 60;
+const SPOOL_REPLAY_INTERVAL: u64 = 30;
+const VEC_SZ: usize = 64;
+const CHAN_UNK: &str = "UNKNOWN";
+const NICK_UNK: &str = "UNKNOWN";
+
+// How the lines are read for URLs, replaced as a whole when the config is reloaded
+struct LineRules {
+    re_nick: Regex,
+    extractor: UrlExtractor,
+    rules: RuleSet,
+}
+
+impl LineRules {
+    fn new(cfg: &ConfigCommon) -> anyhow::Result<Self> {
+        if !cfg.regex_url.is_empty() {
+            warn!("regex_url is set and replaces the built-in URL extractor, remove it to use url_schemes");
+        }
+        Ok(Self {
+            re_nick: Regex::new(&cfg.regex_nick)?,
+            extractor: cfg.url_extractor()?,
+            rules: cfg.url_rules()?,
+        })
+    }
+}
+
+struct IrcCtx {
+    ts: i64,
+    chan: String,
+    msg: String,
+}
+
+// Tails the irssi logs and stores the URLs seen, reading the whole history first when asked to.
+// With apply_rules only removes the stored sightings that the rules deny, or with dry_run lists them.
+pub async fn run(cfg: &ConfigCommon, read_history: bool, apply_rules: bool, dry_run: bool) -> anyhow::Result<()> {
+    check_cmd::startup(cfg, DAEMON).await?;
+    let db = start_db(cfg).await?;
+    let shutdown = Shutdown::listen(cfg)?;
+    // a SIGHUP while reading the history is handled once tailing starts
+    let mut reload = Reload::listen()?;
+    start_status(cfg, DAEMON, &db);
+
+    let mut chans: HashMap<ffi::OsString, String> = HashMap::with_capacity(VEC_SZ);
+    let mut log_files: Vec<fs::DirEntry> = Vec::with_capacity(VEC_SZ);
+
+    debug!("Scanning dir {}", &cfg.irc_log_dir);
+    let re_log = Regex::new(&cfg.regex_log)?;
+    for log_fd in fs::read_dir(&cfg.irc_log_dir)? {
+        let log_f = log_fd?;
+        if let Some(re_match) = re_log.captures(log_f.file_name().to_string_lossy().as_ref()) {
+            chans.insert(
+                log_f
+                    .path()
+                    .file_name()
+                    .ok_or_else(|| anyhow!("no filename"))?
+                    .to_os_string(),
+                re_match[1].to_string(),
+            );
+            log_files.push(log_f);
+        }
+    }
+    debug!("My logfiles: {log_files:?}");
+    debug!("My chans: {chans:?}");
+
+    let mut line_rules = LineRules::new(cfg)?;
+    if apply_rules {
+        line_rules.rules.apply(&db, dry_run).await?;
+        return Ok(());
+    }
+    let mut lmux = MuxedLines::new()?;
+    let chan_unk = CHAN_UNK.to_string();
+    let parser = LogParser::new()?;
+    let mut clocks: HashMap<String, LogClock> = HashMap::with_capacity(VEC_SZ);
+    health().set_ready();
+    if read_history {
+        // Seed the database with all the old log lines too
+        info!("Reading history...");
+
+        // Save the start time to measure elapsed
+        let start_ts = time::Instant::now();
+
+        // rotated logs of a channel continue where the previous file ended
+        log_files.sort_by(|a, b| {
+            let (a, b) = (a.file_name(), b.file_name());
+            log_age_order(&a.to_string_lossy()).cmp(&log_age_order(&b.to_string_lossy()))
+        });
+
+        let mut import = db.bulk_import().await?;
+        // topic changes are few, they are written once the sightings are in
+        let mut topics = Vec::new();
+        let mut progress = HistoryProgress::new(
+            log_files.len(),
+            cfg.harvest.progress_interval.unwrap_or(PROGRESS_INTERVAL),
+        );
+        for log_f in &log_files {
+            let log_nopath = log_f
+                .path()
+                .file_name()
+                .ok_or_else(|| anyhow!("no filename"))?
+                .to_os_string();
+            let chan = chans.get(&log_nopath).unwrap_or(&chan_unk);
+            let clock = clocks
+                .entry(chan.to_string())
+                .or_insert_with(|| LogClock::new(cfg.log_tz(chan)));
+            // irssi starts every log with "Log opened", this only matters for truncated files
+            if !clock.has_date() {
+                let first_date = parser.first_date(
+                    io::BufReader::new(fs::File::open(log_f.path())?)
+                        .lines()
+                        .map_while(Result::ok),
+                );
+                // the creation time is the closest to the first lines, the mtime is the last write
+                let created = log_f.metadata().and_then(|m| m.created().or_else(|_| m.modified()));
+                match (first_date, created) {
+                    (Some(date), _) => clock.set_fallback_date(date),
+                    (None, Ok(created)) => clock.set_fallback_date(DateTime::<Utc>::from(created).date_naive()),
+                    (None, Err(_)) => {}
+                }
+            }
+            let reader = io::BufReader::new(fs::File::open(log_f.path())?);
+            for line in reader.lines() {
+                // what has been read so far still gets committed
+                if shutdown.is_requested() {
+                    break;
+                }
+                let msg = line?;
+                let Some(ts) = clock.update(&parser, &msg) else {
+                    continue;
+                };
+                topics.extend(topic_change(&parser, ts, chan, &msg));
+
+                let urls = handle_ircmsg(
+                    &line_rules,
+                    &parser,
+                    IrcCtx {
+                        ts,
+                        chan: chan.to_string(),
+                        msg,
+                    },
+                );
+                progress.lines += 1;
+                progress.urls += urls.len() as u64;
+                import.add(urls).await?;
+                progress.report(false);
+            }
+            if shutdown.is_requested() {
+                break;
+            }
+            progress.files += 1;
+            // OK all history processed, add the file for live processing from now onwards
+            lmux.add_file(log_f.path()).await?;
+        }
+        let n_new = import.finish().await?;
+        metrics().urls_inserted.inc_by(n_new);
+        let n_topics = db.add_topics(&topics).await?;
+        progress.report(true);
+        line_rules.rules.log_hits();
+        let interrupted = shutdown.is_requested();
+        info!(
+            "History read {} in {:.3} s, {n_new} new sighting(s), {} already in the database, \
+            {n_topics} new topic change(s)",
+            if interrupted { "interrupted" } else { "completed" },
+            start_ts.elapsed().as_millis() as f64 / 1000.0,
+            progress.urls - n_new
+        );
+        if interrupted {
+            info!("Re-run with --read-history to read the rest");
+            return Ok(());
+        }
+    } else {
+        for log_f in &log_files {
+            lmux.add_file(log_f.path()).await?;
+        }
+    }
+
+    info!("Starting live processing...");
+    // each file has its own clock, starting from where history reading left the channel
+    let mut file_clocks: HashMap<ffi::OsString, LogClock> = HashMap::with_capacity(VEC_SZ);
+    let mut spool = Spool::open(&cfg.spool_file)?;
+    let mut cfg = cfg.clone();
+    let mut replay_timer = spool_replay_timer(&cfg);
+    loop {
+        let msg_line = tokio::select! {
+            res = lmux.next_line() => match res {
+                Ok(Some(msg_line)) => msg_line,
+                _ => break,
+            },
+            _ = shutdown.requested() => break,
+            _ = replay_timer.tick() => {
+                health().beat();
+                if spool.depth() > 0 && let Err(e) = spool.replay(&db).await {
+                    error!("{e}");
+                }
+                continue;
+            }
+            _ = reload.requested() => {
+                // tailing goes on, the lines that arrived meanwhile are read with the new config
+                let Some(new_cfg) = reload_config(&cfg, DAEMON, &db).await else {
+                    continue;
+                };
+                match LineRules::new(&new_cfg) {
+                    Ok(new_rules) => {
+                        line_rules = new_rules;
+                        for (filename, clock) in file_clocks.iter_mut() {
+                            clock.set_tz(new_cfg.log_tz(chans.get(filename).unwrap_or(&chan_unk)));
+                        }
+                        for (chan, clock) in clocks.iter_mut() {
+                            clock.set_tz(new_cfg.log_tz(chan));
+                        }
+                        if new_cfg.harvest.spool_replay_interval != cfg.harvest.spool_replay_interval {
+                            replay_timer = spool_replay_timer(&new_cfg);
+                        }
+                        cfg = new_cfg;
+                    }
+                    Err(e) => error!("Config reload rejected, keeping the current config: {e}"),
+                }
+                continue;
+            }
+        };
+        health().beat();
+        let filename = msg_line.source().file_name().unwrap_or_else(|| ffi::OsStr::new("NONE"));
+        let chan = chans.get(filename).unwrap_or(&chan_unk);
+        let msg = msg_line.line();
+        let clock = file_clocks.entry(filename.to_os_string()).or_insert_with(|| {
+            clocks.get(chan).cloned().unwrap_or_else(|| {
+                let tz = cfg.log_tz(chan);
+                let mut clock = LogClock::new(tz);
+                clock.set_fallback_date(tz.today());
+                clock
+            })
+        });
+
+        // wall clock time only when the line does not tell a sensible time
+        let now = Utc::now().timestamp();
+        let clock_slack = cfg.harvest.clock_slack.unwrap_or(CLOCK_SLACK) as i64;
+        let ts = match clock.update(&parser, msg) {
+            Some(ts) if ts <= now + clock_slack => ts,
+            ts => {
+                debug!("No usable log time ({ts:?}), using current time");
+                now
+            }
+        };
+
+        if let Some(topic) = topic_change(&parser, ts, chan, msg) {
+            match db.add_topics(&[topic]).await {
+                Ok(n) => info!("Recorded {n} topic change(s) on {chan}"),
+                Err(e) => error!("Could not record the topic change on {chan}: {e}"),
+            }
+        }
+
+        let urls = handle_ircmsg(
+            &line_rules,
+            &parser,
+            IrcCtx {
+                ts,
+                chan: chan.to_string(),
+                msg: msg.to_string(),
+            },
+        );
+        if urls.is_empty() {
+            continue;
+        }
+        // keep the order, nothing goes past the spool until it has been replayed
+        if spool.depth() > 0 {
+            spool.append(&urls)?;
+            continue;
+        }
+        match db.add_sightings(&urls).await {
+            Ok(n) => {
+                metrics().urls_inserted.inc_by(n);
+                info!("Inserted {n} row(s)");
+            }
+            Err(e) => {
+                error!("Database unavailable, spooling {} url(s): {e}", urls.len());
+                spool.append(&urls)?;
+            }
+        }
+    }
+    if spool.depth() > 0 {
+        info!("Leaving {} sighting(s) in the spool for the next start", spool.depth());
+    }
+
+    Ok(())
+}
+
+fn spool_replay_timer(cfg: &ConfigCommon) -> tokio::time::Interval {
+    let interval = cfg.harvest.spool_replay_interval.unwrap_or(SPOOL_REPLAY_INTERVAL);
+    tokio::time::interval(Duration::new(interval, 0))
+}
+
+struct HistoryProgress {
+    start: time::Instant,
+    last_report: time::Instant,
+    // seconds between the reports
+    interval: u64,
+    n_files: usize,
+    files: usize,
+    lines: u64,
+    urls: u64,
+}
+
+impl HistoryProgress {
+    fn new(n_files: usize, interval: u64) -> Self {
+        let now = time::Instant::now();
+        Self {
+            start: now,
+            last_report: now,
+            interval,
+            n_files,
+            files: 0,
+            lines: 0,
+            urls: 0,
+        }
+    }
+
+    fn report(&mut self, done: bool) {
+        if !done && self.last_report.elapsed().as_secs() < self.interval {
+            return;
+        }
+        self.last_report = time::Instant::now();
+        health().beat();
+        let secs = self.start.elapsed().as_secs_f64().max(0.001);
+        info!(
+            "History: {}/{} files, {} lines, {} urls, {:.0} lines/s, {:.0} urls/s",
+            self.files,
+            self.n_files,
+            self.lines,
+            self.urls,
+            self.lines as f64 / secs,
+            self.urls as f64 / secs
+        );
+    }
+}
+
+fn topic_change(parser: &LogParser, ts: i64, chan: &str, msg: &str) -> Option<TopicCtx> {
+    let topic = parser.topic(msg)?;
+    Some(TopicCtx {
+        ts,
+        chan: chan.to_string(),
+        nick: topic.nick.to_string(),
+        topic: topic.topic.truncate_chars(MSG_MAX_LEN, "..."),
+    })
+}
+
+// Returns the URL sightings found in the message
+fn handle_ircmsg(line_rules: &LineRules, parser: &LogParser, ctx: IrcCtx) -> Vec<UrlCtx> {
+    // Do we have nick in the msg?
+    let nick = &match line_rules.re_nick.captures(&ctx.msg) {
+        Some(nick_match) => nick_match[1].to_string(),
+        // regex_nick does not know about the "-!- nick changed the topic" lines
+        None => parser.topic(&ctx.msg).map_or(NICK_UNK, |t| t.nick).to_string(),
+    };
+
+    let mut urls = Vec::new();
+    let mut message = None;
+    for url in line_rules.extractor.find(&ctx.msg) {
+        info!("Detected url: {chan} {nick} {url}", chan = ctx.chan);
+        if !line_rules.rules.allows(&ctx.chan, nick, &url) {
+            info!("URL denied by the rules.");
+            continue;
+        }
+        // only lines with urls are worth the effort
+        let (kind, msg) = message.get_or_insert_with(|| {
+            let (kind, text) = parser.message(&ctx.msg);
+            (kind, text.truncate_chars(MSG_MAX_LEN, "..."))
+        });
+        urls.push(UrlCtx {
+            ts: ctx.ts,
+            chan: ctx.chan.clone(),
+            nick: nick.to_string(),
+            url,
+            msg: msg.clone(),
+            kind: *kind,
+        });
+    }
+    urls
+}
+// EOF
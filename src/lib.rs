@@ -16,16 +16,47 @@ pub use serde::{Deserialize, Serialize};
 pub use tokio::time::{Duration, sleep};
 pub use tracing::*;
 
+pub use asset_util::*;
 pub use config::*;
 pub use db_util::*;
 pub use hash_util::*;
+pub use log_util::*;
+pub use metrics_util::*;
+pub use output_util::*;
+pub use reload_util::*;
+pub use rule_util::*;
+pub use shutdown_util::*;
+pub use spool_util::*;
+pub use status_util::*;
 pub use str_util::*;
+pub use tera_util::*;
+pub use url_util::*;
 pub use web_util::*;
 
+// the daemons and the admin commands, used through the module name
+pub mod admin_cmd;
+pub mod check_cmd;
+pub mod generate_cmd;
+pub mod harvest_cmd;
+pub mod meta_cmd;
+pub mod migrate_cmd;
+pub mod serve_cmd;
+
+pub mod asset_util;
 pub mod config;
 pub mod db_util;
 pub mod hash_util;
+pub mod log_util;
+pub mod metrics_util;
+pub mod output_util;
+pub mod reload_util;
+pub mod rule_util;
+pub mod shutdown_util;
+pub mod spool_util;
+pub mod status_util;
 pub mod str_util;
+pub mod tera_util;
+pub mod url_util;
 pub mod web_util;
 
 // EOF
@@ -0,0 +1,439 @@
+// log_util.rs
+
+use std::cmp::Reverse;
+
+use crate::*;
+
+// Match most message lines, example:
+// "13:37 <@sjm> 1337"
+const RE_HOURMIN: &str = r"^(\d\d):(\d\d)\s";
+// Match example line:
+// "--- Day changed Fri Aug 13 2021"
+const RE_DAYCHANGE: &str = r"^--- Day changed \w+ (\w+ \d+ \d+)";
+// Match example line:
+// "--- Log opened Sun Aug 08 13:37:42 2021"
+const RE_TIMESTAMP: &str = r"^--- Log opened \w+ (\w+ \d+) (\d+:\d+:\d+) (\d+)";
+
+// What comes after the time on the message lines, examples:
+// "<@sjm> 1337"
+// " * sjm waves"
+// "-sjm(~sjm@example.com)- hello"
+// "-!- sjm changed the topic of #chan to: news"
+const RE_MSG_PRIVMSG: &str = r"^\d\d:\d\d <[^>]*> (.*)$";
+// actions keep the nick, it is part of the sentence
+const RE_MSG_ACTION: &str = r"^\d\d:\d\d\s+\* (.*)$";
+const RE_MSG_SERVER: &str = r"^\d\d:\d\d -!- (.*)$";
+const RE_MSG_NOTICE: &str = r"^\d\d:\d\d -\S+- (.*)$";
+// Match example line:
+// "13:37 -!- sjm changed the topic of #chan to: news"
+const RE_TOPIC: &str = r"^\d\d:\d\d -!- (\S+) changed the topic of (\S+) to: ?(.*)$";
+pub const MSG_MAX_LEN: usize = 400;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MsgKind {
+    #[default]
+    Privmsg,
+    Action,
+    Notice,
+    Topic,
+    Join,
+    Part,
+    Other,
+}
+
+impl MsgKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MsgKind::Privmsg => "privmsg",
+            MsgKind::Action => "action",
+            MsgKind::Notice => "notice",
+            MsgKind::Topic => "topic",
+            MsgKind::Join => "join",
+            MsgKind::Part => "part",
+            MsgKind::Other => "other",
+        }
+    }
+}
+
+impl fmt::Display for MsgKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogTopic<'a> {
+    pub nick: &'a str,
+    pub chan: &'a str,
+    pub topic: &'a str,
+}
+
+// The zone irssi wrote the log times in, see log_timezone in the config
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogTz {
+    Local,
+    Zone(Tz),
+}
+
+impl LogTz {
+    // Ambiguous times (clocks turned back) resolve to the earliest instant that does not go
+    // backwards from `after`, nonexistent times (clocks turned forward) use the offset from
+    // before the gap. Both are deterministic, so re-reading the same logs gives the same result.
+    pub fn resolve(&self, naive: &NaiveDateTime, after: Option<i64>) -> Option<i64> {
+        match self {
+            LogTz::Local => resolve_in(&Local, naive, after),
+            LogTz::Zone(tz) => resolve_in(tz, naive, after),
+        }
+    }
+
+    pub fn today(&self) -> NaiveDate {
+        match self {
+            LogTz::Local => Local::now().date_naive(),
+            LogTz::Zone(tz) => Utc::now().with_timezone(tz).date_naive(),
+        }
+    }
+}
+
+fn resolve_in<T: TimeZone>(tz: &T, naive: &NaiveDateTime, after: Option<i64>) -> Option<i64> {
+    match tz.from_local_datetime(naive) {
+        LocalResult::Single(dt) => Some(dt.timestamp()),
+        LocalResult::Ambiguous(earliest, latest) => match after {
+            Some(after) if earliest.timestamp() < after => Some(latest.timestamp()),
+            _ => Some(earliest.timestamp()),
+        },
+        LocalResult::None => {
+            // DST gaps are at most a couple of hours
+            (1..=3).find_map(|h| {
+                let before = *naive - TimeDelta::hours(h);
+                match tz.from_local_datetime(&before) {
+                    LocalResult::Single(dt) => Some(dt.timestamp() + h * 3600),
+                    _ => None,
+                }
+            })
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct LogParser {
+    re_hourmin: Regex,
+    re_daychange: Regex,
+    re_timestamp: Regex,
+    re_msg_privmsg: Regex,
+    re_msg_action: Regex,
+    re_msg_server: Regex,
+    re_msg_notice: Regex,
+    re_topic: Regex,
+}
+
+impl LogParser {
+    pub fn new() -> anyhow::Result<Self> {
+        Ok(Self {
+            re_hourmin: Regex::new(RE_HOURMIN)?,
+            re_daychange: Regex::new(RE_DAYCHANGE)?,
+            re_timestamp: Regex::new(RE_TIMESTAMP)?,
+            re_msg_privmsg: Regex::new(RE_MSG_PRIVMSG)?,
+            re_msg_action: Regex::new(RE_MSG_ACTION)?,
+            re_msg_server: Regex::new(RE_MSG_SERVER)?,
+            re_msg_notice: Regex::new(RE_MSG_NOTICE)?,
+            re_topic: Regex::new(RE_TOPIC)?,
+        })
+    }
+
+    // The kind of the message and its text without the time and nick decorations
+    pub fn message<'a>(&self, line: &'a str) -> (MsgKind, &'a str) {
+        let text = |m: regex::Captures<'a>| m.get(1).map_or("", |t| t.as_str());
+        if let Some(m) = self.re_msg_privmsg.captures(line) {
+            (MsgKind::Privmsg, text(m))
+        } else if let Some(m) = self.re_msg_action.captures(line) {
+            (MsgKind::Action, text(m))
+        } else if let Some(m) = self.re_msg_server.captures(line) {
+            let msg = text(m);
+            let kind = if self.re_topic.is_match(line) {
+                MsgKind::Topic
+            } else if msg.contains(" has joined ") {
+                MsgKind::Join
+            } else if msg.contains(" has left ") || msg.contains(" has quit ") {
+                MsgKind::Part
+            } else {
+                MsgKind::Other
+            };
+            (kind, msg)
+        } else if let Some(m) = self.re_msg_notice.captures(line) {
+            (MsgKind::Notice, text(m))
+        } else {
+            (MsgKind::Other, line)
+        }
+    }
+
+    // The topic change on the line, if any
+    pub fn topic<'a>(&self, line: &'a str) -> Option<LogTopic<'a>> {
+        let m = self.re_topic.captures(line)?;
+        Some(LogTopic {
+            nick: m.get(1)?.as_str(),
+            chan: m.get(2)?.as_str(),
+            topic: m.get(3)?.as_str(),
+        })
+    }
+
+    fn hourmin(&self, line: &str) -> Option<NaiveTime> {
+        let m = self.re_hourmin.captures(line)?;
+        NaiveTime::from_hms_opt(m[1].parse().ok()?, m[2].parse().ok()?, 0)
+    }
+
+    fn daychange(&self, line: &str) -> Option<NaiveDate> {
+        let m = self.re_daychange.captures(line)?;
+        NaiveDate::parse_from_str(&m[1], "%b %d %Y").ok()
+    }
+
+    fn timestamp(&self, line: &str) -> Option<NaiveDateTime> {
+        let m = self.re_timestamp.captures(line)?;
+        NaiveDateTime::parse_from_str(&format!("{} {} {}", &m[1], &m[3], &m[2]), "%b %d %Y %H:%M:%S").ok()
+    }
+
+    // The date of the first lines of a log, from its first date marker.
+    // Lines before a "Day changed" are from the day before.
+    pub fn first_date<I: IntoIterator<Item = String>>(&self, lines: I) -> Option<NaiveDate> {
+        let mut messages_before = false;
+        for line in lines {
+            if let Some(date) = self.daychange(&line) {
+                return match messages_before {
+                    true => date.pred_opt(),
+                    false => Some(date),
+                };
+            } else if let Some(dt) = self.timestamp(&line) {
+                return Some(dt.date());
+            }
+            messages_before |= self.hourmin(&line).is_some();
+        }
+        None
+    }
+}
+
+// Sort key for reading the logs oldest first: rotated logs like "#chan.log.2" and "#chan.log.1"
+// come before the current "#chan.log", otherwise the names are in order.
+pub fn log_age_order(name: &str) -> (&str, Reverse<Option<u64>>) {
+    match name.rsplit_once('.') {
+        Some((base, n)) if !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()) => (base, Reverse(n.parse().ok())),
+        _ => (name, Reverse(None)),
+    }
+}
+
+// Follows the date and time through a log as it is read line by line.
+// Keep one per channel so that rotated log files carry the date over.
+#[derive(Debug, Clone)]
+pub struct LogClock {
+    tz: LogTz,
+    current: Option<NaiveDateTime>,
+    ts: Option<i64>,
+}
+
+impl LogClock {
+    pub fn new(tz: LogTz) -> Self {
+        Self {
+            tz,
+            current: None,
+            ts: None,
+        }
+    }
+
+    // The times read from now on are in the new zone
+    pub fn set_tz(&mut self, tz: LogTz) {
+        self.tz = tz;
+    }
+
+    pub fn has_date(&self) -> bool {
+        self.current.is_some()
+    }
+
+    // Used until the log itself tells the date
+    pub fn set_fallback_date(&mut self, date: NaiveDate) {
+        if self.current.is_none() {
+            self.current = date.and_hms_opt(0, 0, 0);
+        }
+    }
+
+    // Returns the timestamp of the line, or None if the time is not known yet
+    pub fn update(&mut self, parser: &LogParser, line: &str) -> Option<i64> {
+        // most common case first
+        let naive = if let Some(time) = parser.hourmin(line) {
+            let date = self.current?.date();
+            date.and_time(time)
+        } else if let Some(date) = parser.daychange(line) {
+            date.and_hms_opt(0, 0, 0)?
+        } else if let Some(dt) = parser.timestamp(line) {
+            trace!("Found timestamp {dt:?}");
+            dt
+        } else {
+            return self.ts;
+        };
+
+        self.current = Some(naive);
+        if let Some(ts) = self.tz.resolve(&naive, self.ts) {
+            self.ts = Some(ts);
+        }
+        self.ts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn helsinki() -> LogTz {
+        LogTz::Zone("Europe/Helsinki".parse().unwrap())
+    }
+
+    fn read(clock: &mut LogClock, lines: &[&str]) -> Vec<Option<i64>> {
+        let parser = LogParser::new().unwrap();
+        lines.iter().map(|l| clock.update(&parser, l)).collect()
+    }
+
+    #[test]
+    fn parses_irssi_markers() {
+        let mut clock = LogClock::new(LogTz::Zone(Tz::UTC));
+        let ts = read(
+            &mut clock,
+            &[
+                "13:37 <@sjm> too early, no date yet",
+                "--- Log opened Sun Aug 08 13:37:42 2021",
+                "13:38 <@sjm> https://example.com",
+                "--- Day changed Mon Aug 09 2021",
+                "00:01 <@sjm> https://example.com",
+            ],
+        );
+        assert_eq!(
+            ts,
+            [
+                None,
+                Some(1628429862),
+                Some(1628429880),
+                Some(1628467200),
+                Some(1628467260)
+            ]
+        );
+    }
+
+    #[test]
+    fn message_kinds() {
+        let parser = LogParser::new().unwrap();
+        let kinds = [
+            (
+                "13:37 <@sjm> see https://example.com",
+                MsgKind::Privmsg,
+                "see https://example.com",
+            ),
+            (
+                "13:37  * sjm likes https://example.com",
+                MsgKind::Action,
+                "sjm likes https://example.com",
+            ),
+            (
+                "13:37 -sjm(~sjm@example.com)- https://example.com",
+                MsgKind::Notice,
+                "https://example.com",
+            ),
+            (
+                "13:37 -!- sjm changed the topic of #test to: https://example.com",
+                MsgKind::Topic,
+                "sjm changed the topic of #test to: https://example.com",
+            ),
+            (
+                "13:37 -!- sjm [~sjm@example.com] has quit [https://example.com]",
+                MsgKind::Part,
+                "sjm [~sjm@example.com] has quit [https://example.com]",
+            ),
+        ];
+        for (line, kind, text) in kinds {
+            assert_eq!(parser.message(line), (kind, text), "{line}");
+        }
+
+        let topic = parser.topic("13:37 -!- sjm changed the topic of #test to: news https://example.com");
+        assert_eq!(
+            topic,
+            Some(LogTopic {
+                nick: "sjm",
+                chan: "#test",
+                topic: "news https://example.com"
+            })
+        );
+        assert_eq!(parser.topic("13:37 <sjm> sjm changed the topic of #test to: x"), None);
+    }
+
+    #[test]
+    fn dst_gap_uses_offset_before_the_gap() {
+        // 2021-03-28 03:00 EET jumped to 04:00 EEST, 03:30 never happened
+        let mut clock = LogClock::new(helsinki());
+        let ts = read(
+            &mut clock,
+            &[
+                "--- Day changed Sun Mar 28 2021",
+                "02:59 < a> x",
+                "03:30 < a> x",
+                "04:00 < a> x",
+            ],
+        );
+        // 00:59 UTC, 01:30 UTC (03:30 EET), 01:00 UTC (04:00 EEST)
+        assert_eq!(ts[1..], [Some(1616893140), Some(1616895000), Some(1616893200)]);
+    }
+
+    #[test]
+    fn dst_overlap_follows_the_log_order() {
+        // 2021-10-31 04:00 EEST went back to 03:00 EET, 03:00-03:59 happened twice
+        let mut clock = LogClock::new(helsinki());
+        let ts = read(
+            &mut clock,
+            &[
+                "--- Day changed Sun Oct 31 2021",
+                "03:10 < a> first pass",
+                "03:50 < a> first pass",
+                "03:05 < a> second pass",
+                "03:50 < a> second pass",
+            ],
+        );
+        // 00:10, 00:50, 01:05 and 01:50 UTC
+        assert_eq!(
+            ts[1..],
+            [Some(1635639000), Some(1635641400), Some(1635642300), Some(1635645000)]
+        );
+    }
+
+    #[test]
+    fn clock_carries_over_to_the_next_file() {
+        let mut clock = LogClock::new(LogTz::Zone(Tz::UTC));
+        read(&mut clock, &["--- Log opened Sun Aug 08 23:50:00 2021"]);
+        // the rotated file continues without a Log opened line of its own
+        clock.set_fallback_date(NaiveDate::from_ymd_opt(2000, 1, 1).unwrap());
+        assert_eq!(read(&mut clock, &["23:55 < a> x"]), [Some(1628466900)]);
+    }
+
+    #[test]
+    fn first_date_of_a_truncated_log() {
+        let parser = LogParser::new().unwrap();
+        let lines = |l: &[&str]| l.iter().map(|l| l.to_string()).collect::<Vec<_>>();
+        let date = |y, m, d| NaiveDate::from_ymd_opt(y, m, d);
+        assert_eq!(
+            parser.first_date(lines(&["23:55 < a> x", "--- Day changed Mon Aug 09 2021"])),
+            date(2021, 8, 8)
+        );
+        assert_eq!(
+            parser.first_date(lines(&["--- Day changed Mon Aug 09 2021", "00:01 < a> x"])),
+            date(2021, 8, 9)
+        );
+        assert_eq!(
+            parser.first_date(lines(&["--- Log opened Sun Aug 08 13:37:42 2021"])),
+            date(2021, 8, 8)
+        );
+        assert_eq!(parser.first_date(lines(&["13:37 < a> x"])), None);
+    }
+
+    #[test]
+    fn rotated_logs_oldest_first() {
+        let mut names = ["#b.log", "#a.log.1", "#a.log", "#a.log.10", "#a.log.2"];
+        names.sort_by_key(|n| log_age_order(n));
+        assert_eq!(names, ["#a.log.10", "#a.log.2", "#a.log.1", "#a.log", "#b.log"]);
+    }
+}
+
+// EOF
@@ -0,0 +1,157 @@
+// meta_cmd.rs
+
+use crate::*;
+
+// the status_listen key
+pub const DAEMON: &str = "urllog_meta";
+const STR_NA: &str = "(N/A)";
+const STR_ERR: &str = "(Error)";
+// defaults for the [meta] settings
+const BATCH_SIZE: usize = 10;
+const TITLE_MAX_LEN: usize = 400;
+
+#[derive(Debug, PartialEq, Eq)]
+enum ProcessMode {
+    Backlog,
+    Live,
+}
+
+// Fetches the metadata of new URLs as they come, or with backlog of all the URLs still missing it and exits
+pub async fn run(cfg: &ConfigCommon, backlog: bool) -> anyhow::Result<()> {
+    check_cmd::startup(cfg, DAEMON).await?;
+    let dbc = start_db(cfg).await?;
+    let shutdown = Shutdown::listen(cfg)?;
+    start_status(cfg, DAEMON, &dbc);
+
+    let mut reload = Reload::listen()?;
+
+    if backlog {
+        process_meta(&dbc, cfg, ProcessMode::Backlog, &shutdown, &mut reload).await
+    } else {
+        process_meta(&dbc, cfg, ProcessMode::Live, &shutdown, &mut reload).await
+    }
+}
+
+async fn process_meta(
+    dbc: &DbCtx,
+    cfg: &ConfigCommon,
+    mode: ProcessMode,
+    shutdown: &Shutdown,
+    reload: &mut Reload,
+) -> anyhow::Result<()> {
+    let mut cfg = cfg.clone();
+    let order = match mode {
+        ProcessMode::Backlog => SortOrder::Asc,
+        ProcessMode::Live => SortOrder::Desc,
+    };
+
+    let mut listener = match mode {
+        ProcessMode::Backlog => None,
+        ProcessMode::Live => Some(dbc.listener().await?),
+    };
+    health().set_ready();
+
+    loop {
+        info!("Starting {mode:?} processing");
+        metrics().meta_pending.set(dbc.pending_meta_count().await?);
+        loop {
+            let rows = dbc
+                .pending_meta(order, cfg.meta.batch_size.unwrap_or(BATCH_SIZE))
+                .await?;
+            let Some(last) = rows.last() else {
+                break;
+            };
+            if last.seen > 0 {
+                info!("*** PROCESSING *** at {}", &last.seen.ts_short_y());
+            }
+            for row in &rows {
+                // the fetch at hand is finished and stored first
+                if shutdown.is_requested() {
+                    return Ok(());
+                }
+                if reload.take()
+                    && let Some(new_cfg) = reload_config(&cfg, DAEMON, dbc).await
+                {
+                    cfg = new_cfg;
+                }
+                update_meta(dbc, &cfg.meta, row.id, &row.url).await?;
+                health().beat();
+                metrics().meta_pending.dec();
+            }
+        }
+
+        if mode == ProcessMode::Backlog {
+            // Backlog processing ends once all currently missing metadata is handled.
+            break;
+        }
+
+        info!("Waiting for database updates");
+        let listener = listener.as_mut().expect("live mode has a listener");
+        tokio::select! {
+            res = wait_for_change(listener, dbc) => {
+                if res? == DbChange::Reconnected {
+                    warn!("Database listener reconnected; reconciling current state");
+                }
+            }
+            _ = reload.requested() => {
+                if let Some(new_cfg) = reload_config(&cfg, DAEMON, dbc).await {
+                    cfg = new_cfg;
+                }
+            }
+            _ = shutdown.requested() => break,
+        }
+        listener.drain();
+    }
+    Ok(())
+}
+
+pub async fn update_meta<R: UrlRepo>(repo: &R, mc: &MetaConfig, url_id: i32, url_s: &str) -> anyhow::Result<()> {
+    let (mut title, lang, descr) = match get_text_body(url_s, mc).await {
+        Err(e) => (format!("(URL fetch error: {e:?})"), STR_ERR.into(), STR_ERR.into()),
+        Ok(None) => (STR_NA.into(), STR_NA.into(), STR_NA.into()),
+        Ok(Some((body, _ct))) => match webpage::HTML::from_string(body, None) {
+            Err(e) => (format!("(Webpage HTML error: {e:?})"), STR_ERR.into(), STR_ERR.into()),
+            Ok(html) => (
+                html.title.unwrap_or_else(|| STR_NA.to_owned()),
+                html.language.unwrap_or_else(|| STR_NA.to_owned()),
+                html.description.unwrap_or_else(|| STR_NA.to_owned()),
+            ),
+        },
+    };
+
+    // Cleanup the title
+    title = title.ws_collapse();
+    let len = title.len();
+    let title_max_len = mc.title_max_len.unwrap_or(TITLE_MAX_LEN);
+    if len > title_max_len {
+        let mut i = title_max_len.saturating_sub(8);
+        loop {
+            // find a UTF-8 code point boundary to safely split at
+            if title.is_char_boundary(i) || i >= len {
+                break;
+            }
+            i += 1;
+        }
+        if i < len {
+            let (s1, _) = title.split_at(i);
+            title = format!("{}...", s1);
+        } else {
+            error!("Did not find char boundary, should never happen.");
+        }
+    }
+
+    info!("URL metadata:\nid: {url_id}\nurl: {url_s}\nlang: {lang}\ntitle: {title}\ndescr: {descr}",);
+    // not inside info!() which skips evaluating its arguments unless enabled
+    let n = repo
+        .add_meta(&MetaCtx {
+            url_id,
+            lang,
+            title,
+            descr,
+        })
+        .await?;
+    info!("Inserted {n} row(s)");
+
+    Ok(())
+}
+// EOF
@@ -0,0 +1,103 @@
+// metrics_util.rs
+
+use std::sync::LazyLock;
+
+use axum::{http::header, response::IntoResponse};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+
+const METRICS_PREFIX: &str = "urlharvest";
+pub const METRICS_PATH: &str = "/metrics";
+
+// Everything the daemons report, each one only updates its own part
+pub struct Metrics {
+    registry: Registry,
+    pub urls_inserted: IntCounter,
+    pub rule_hits: IntCounterVec,
+    pub spool_depth: IntGauge,
+    pub meta_fetch: IntCounterVec,
+    pub meta_pending: IntGauge,
+    pub render_seconds: HistogramVec,
+    pub http_request_seconds: HistogramVec,
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(|| Metrics::new().expect("metric definitions are valid"));
+
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+impl Metrics {
+    fn new() -> anyhow::Result<Self> {
+        let registry = Registry::new_custom(Some(METRICS_PREFIX.to_string()), None)?;
+        let m = Self {
+            urls_inserted: IntCounter::new("urls_inserted_total", "URL sightings inserted into the database")?,
+            rule_hits: IntCounterVec::new(
+                Opts::new("url_rule_hits_total", "URLs decided by url_rules and url_blacklist"),
+                &["rule", "action"],
+            )?,
+            spool_depth: IntGauge::new("spool_depth", "URL sightings waiting in the spool")?,
+            meta_fetch: IntCounterVec::new(
+                Opts::new("meta_fetch_total", "Metadata fetches by HTTP status or error"),
+                &["status"],
+            )?,
+            meta_pending: IntGauge::new("meta_pending", "URL sightings still waiting for metadata")?,
+            render_seconds: HistogramVec::new(
+                HistogramOpts::new("render_seconds", "Time to render and write a generated page"),
+                &["template"],
+            )?,
+            http_request_seconds: HistogramVec::new(
+                HistogramOpts::new("http_request_seconds", "Time to answer an HTTP request"),
+                &["path", "status"],
+            )?,
+            registry,
+        };
+        m.registry.register(Box::new(m.urls_inserted.clone()))?;
+        m.registry.register(Box::new(m.rule_hits.clone()))?;
+        m.registry.register(Box::new(m.spool_depth.clone()))?;
+        m.registry.register(Box::new(m.meta_fetch.clone()))?;
+        m.registry.register(Box::new(m.meta_pending.clone()))?;
+        m.registry.register(Box::new(m.render_seconds.clone()))?;
+        m.registry.register(Box::new(m.http_request_seconds.clone()))?;
+        Ok(m)
+    }
+
+    // Prometheus text exposition format
+    pub fn encode(&self) -> anyhow::Result<String> {
+        let mut buf = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf)?;
+        Ok(String::from_utf8(buf)?)
+    }
+}
+
+pub async fn get_metrics() -> impl IntoResponse {
+    match metrics().encode() {
+        Ok(body) => (
+            axum::http::StatusCode::OK,
+            [(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+            body,
+        ),
+        Err(e) => (
+            axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+            [(header::CONTENT_TYPE, "text/plain")],
+            format!("Metrics error: {e}"),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_the_registered_metrics() {
+        metrics().urls_inserted.inc_by(3);
+        metrics().meta_fetch.with_label_values(&["404"]).inc();
+        let text = metrics().encode().unwrap();
+        assert!(text.contains("# TYPE urlharvest_urls_inserted_total counter"), "{text}");
+        assert!(text.contains("urlharvest_meta_fetch_total{status=\"404\"} 1"), "{text}");
+    }
+}
+
+// EOF
@@ -0,0 +1,82 @@
+// migrate_cmd.rs
+
+// provides `try_next`
+use futures::TryStreamExt;
+use sqlx::{Connection, FromRow, SqliteConnection};
+
+use crate::*;
+
+const TX_SZ: usize = 1024;
+
+const SQL_READ_URL: &str = "select id, seen, channel, nick, url from url";
+const SQL_READ_META: &str = "select url_id, lang, title, desc from url_meta";
+
+#[derive(Debug, FromRow)]
+struct DbReadMeta {
+    url_id: i32,
+    lang: String,
+    title: String,
+    desc: String,
+}
+
+pub const LEGACY_DB: &str = "sqlite:./url.db";
+
+// Brings the database schema up to date, start_db does that for every daemon too
+pub async fn run(cfg: &ConfigCommon) -> anyhow::Result<()> {
+    start_db(cfg).await?;
+    info!("Database schema is up to date");
+    Ok(())
+}
+
+// Copies the urls and metadata from a database of the original urlharvest into the configured one
+pub async fn import_legacy(cfg: &ConfigCommon, legacy_db: &str) -> anyhow::Result<()> {
+    let dbc = start_db(cfg).await?;
+
+    // the legacy database is read as is, url rows already match our DbUrl
+    let mut sqlite = SqliteConnection::connect(legacy_db).await?;
+    let mut url_ids: HashSet<i32> = HashSet::with_capacity(1_000_000);
+
+    let mut batch = Vec::with_capacity(TX_SZ);
+    let mut st_read = sqlx::query_as::<_, DbUrl>(SQL_READ_URL).fetch(&mut sqlite);
+    while let Some(row) = st_read.try_next().await? {
+        url_ids.insert(row.id);
+        batch.push(row);
+        if batch.len() >= TX_SZ {
+            dbc.import_urls(&batch).await?;
+            debug!("Inserted url_id #{}", batch[batch.len() - 1].id);
+            batch.clear();
+        }
+    }
+    drop(st_read);
+    dbc.import_urls(&batch).await?;
+    info!("Imported {} urls.", url_ids.len());
+
+    let mut orphans = 0;
+    let mut batch = Vec::with_capacity(TX_SZ);
+    let mut st_read = sqlx::query_as::<_, DbReadMeta>(SQL_READ_META).fetch(&mut sqlite);
+    while let Some(row) = st_read.try_next().await? {
+        if !url_ids.contains(&row.url_id) {
+            orphans += 1;
+            continue;
+        }
+
+        batch.push(MetaCtx {
+            url_id: row.url_id,
+            lang: row.lang,
+            title: row.title,
+            descr: row.desc,
+        });
+        if batch.len() >= TX_SZ {
+            let n = dbc.import_meta(&batch).await?;
+            debug!("Inserted {n} url_meta rows");
+            batch.clear();
+        }
+    }
+    drop(st_read);
+    dbc.import_meta(&batch).await?;
+    info!("Detected {orphans} orphan url_meta lines.");
+
+    Ok(())
+}
+
+// EOF
@@ -0,0 +1,295 @@
+// output_util.rs
+
+use std::{collections::BTreeMap, io::Write};
+
+use sha2::{Digest, Sha256};
+
+use crate::*;
+
+const GZIP_SUFFIX: &str = "gz";
+const BROTLI_SUFFIX: &str = "br";
+const BROTLI_QUALITY: u32 = 9;
+const BROTLI_LGWIN: u32 = 22;
+const BUF_SZ: usize = 4096;
+pub const ETAG_MANIFEST: &str = "etags.json";
+// the page fingerprints, kept so that a restart does not rewrite every page
+pub const FINGERPRINTS: &str = "fingerprints.json";
+
+// Write via a temporary file so that readers never see a partial file
+pub fn write_atomic<P: AsRef<path::Path>>(filename_out: P, content: &[u8]) -> anyhow::Result<()> {
+    let filename_out = filename_out.as_ref();
+    let mut filename_tmp = filename_out.as_os_str().to_owned();
+    filename_tmp.push(format!(
+        ".{}.{}.tmp",
+        std::process::id(),
+        Utc::now().timestamp_nanos_opt().unwrap_or(0)
+    ));
+    fs::write(&filename_tmp, content)?;
+    fs::rename(&filename_tmp, filename_out)?;
+    Ok(())
+}
+
+pub fn content_etag(content: &[u8]) -> String {
+    let digest = Sha256::digest(content);
+    // 128 bits are plenty for cache validation
+    let hex = digest.iter().take(16).map(|b| format!("{b:02x}")).collect::<String>();
+    format!("\"{hex}\"")
+}
+
+pub fn compress_gzip(content: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut enc = flate2::write::GzEncoder::new(Vec::with_capacity(content.len() / 4), flate2::Compression::best());
+    enc.write_all(content)?;
+    Ok(enc.finish()?)
+}
+
+pub fn compress_brotli(content: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(content.len() / 4);
+    {
+        let mut enc = brotli::CompressorWriter::new(&mut out, BUF_SZ, BROTLI_QUALITY, BROTLI_LGWIN);
+        enc.write_all(content)?;
+        enc.flush()?;
+    }
+    Ok(out)
+}
+
+// A missing or unreadable state file only means that everything is written again
+fn read_state<T: serde::de::DeserializeOwned + Default>(file: path::PathBuf) -> T {
+    match fs::File::open(file) {
+        Ok(f) => serde_json::from_reader(io::BufReader::new(f)).unwrap_or_default(),
+        Err(_) => T::default(),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub etag: String,
+    pub size: u64,
+    pub modified: i64,
+}
+
+// Writes the generated pages under html_dir.
+// Unchanged files are left alone so that their mtime stays valid for caching,
+// compressed siblings are optional and an ETag manifest can be kept for the web server.
+#[derive(Debug)]
+pub struct OutputWriter {
+    base_dir: path::PathBuf,
+    gzip: bool,
+    brotli: bool,
+    manifest_enabled: bool,
+    manifest: BTreeMap<String, ManifestEntry>,
+    manifest_dirty: bool,
+    fingerprints: BTreeMap<String, String>,
+    fingerprints_dirty: bool,
+}
+
+impl OutputWriter {
+    pub fn new(cfg: &ConfigCommon) -> Self {
+        let base_dir = path::PathBuf::from(&cfg.html_dir);
+        let manifest = read_state(base_dir.join(ETAG_MANIFEST));
+        let fingerprints = read_state(base_dir.join(FINGERPRINTS));
+        Self {
+            base_dir,
+            gzip: cfg.output_gzip,
+            brotli: cfg.output_brotli,
+            manifest_enabled: cfg.output_etag_manifest,
+            manifest,
+            manifest_dirty: false,
+            fingerprints,
+            fingerprints_dirty: false,
+        }
+    }
+
+    // Manifest and fingerprint keys are relative to html_dir
+    fn key(&self, filename_out: &path::Path) -> String {
+        filename_out
+            .strip_prefix(&self.base_dir)
+            .unwrap_or(filename_out)
+            .to_string_lossy()
+            .to_string()
+    }
+
+    // Pages embed their generation time, so callers can provide a fingerprint of the
+    // time independent content to decide whether a page needs to be rendered at all.
+    pub fn is_current<P: AsRef<path::Path>>(&self, filename_out: P, fingerprint: &str) -> bool {
+        let filename_out = filename_out.as_ref();
+        self.fingerprints
+            .get(&self.key(filename_out))
+            .is_some_and(|f| f == fingerprint)
+            && filename_out.exists()
+    }
+
+    pub fn set_fingerprint<P: AsRef<path::Path>>(&mut self, filename_out: P, fingerprint: String) {
+        let key = self.key(filename_out.as_ref());
+        if self.fingerprints.get(&key) != Some(&fingerprint) {
+            self.fingerprints.insert(key, fingerprint);
+            self.fingerprints_dirty = true;
+        }
+    }
+
+    // Returns true if the file content actually changed
+    pub fn write<P: AsRef<path::Path>>(&mut self, filename_out: P, content: &[u8]) -> anyhow::Result<bool> {
+        let filename_out = filename_out.as_ref();
+        let unchanged = fs::read(filename_out).is_ok_and(|old| old == content);
+        if unchanged {
+            debug!("Unchanged: {filename_out:?}");
+        } else {
+            write_atomic(filename_out, content)?;
+        }
+
+        // The siblings come after the page, so a sibling older than its page was cut short
+        let page_modified = fs::metadata(filename_out)?.modified()?;
+        for (enabled, suffix) in [(self.gzip, GZIP_SUFFIX), (self.brotli, BROTLI_SUFFIX)] {
+            let mut sibling = filename_out.as_os_str().to_owned();
+            sibling.push(format!(".{suffix}"));
+            let sibling = path::PathBuf::from(sibling);
+            if !enabled {
+                // never leave stale compressed variants behind for the web server to find
+                if sibling.exists() {
+                    fs::remove_file(&sibling)?;
+                }
+            } else if !unchanged
+                || fs::metadata(&sibling)
+                    .and_then(|m| m.modified())
+                    .ok()
+                    .is_none_or(|m| m < page_modified)
+            {
+                let compressed = match suffix {
+                    GZIP_SUFFIX => compress_gzip(content)?,
+                    _ => compress_brotli(content)?,
+                };
+                write_atomic(&sibling, &compressed)?;
+            }
+        }
+
+        if self.manifest_enabled {
+            let key = self.key(filename_out);
+            let etag = content_etag(content);
+            if self.manifest.get(&key).is_none_or(|e| e.etag != etag) {
+                let modified = page_modified.duration_since(time::UNIX_EPOCH)?.as_secs() as i64;
+                self.manifest.insert(
+                    key,
+                    ManifestEntry {
+                        etag,
+                        size: content.len() as u64,
+                        modified,
+                    },
+                );
+                self.manifest_dirty = true;
+            }
+        }
+        Ok(!unchanged)
+    }
+
+    // Removes a page that should no longer be published, with its compressed siblings
+    pub fn remove<P: AsRef<path::Path>>(&mut self, filename_out: P) -> anyhow::Result<()> {
+        let filename_out = filename_out.as_ref();
+        for suffix in ["", GZIP_SUFFIX, BROTLI_SUFFIX] {
+            let mut file = filename_out.as_os_str().to_owned();
+            if !suffix.is_empty() {
+                file.push(format!(".{suffix}"));
+            }
+            match fs::remove_file(&file) {
+                Ok(()) => info!("Removed {file:?}"),
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                Err(_) => {}
+            }
+        }
+        let key = self.key(filename_out);
+        if self.fingerprints.remove(&key).is_some() {
+            self.fingerprints_dirty = true;
+        }
+        if self.manifest.remove(&key).is_some() {
+            self.manifest_dirty = true;
+        }
+        Ok(())
+    }
+
+    // Saves the ETag manifest and the fingerprints after a generation pass
+    pub fn write_state(&mut self) -> anyhow::Result<()> {
+        if self.manifest_enabled && self.manifest_dirty {
+            write_atomic(
+                self.base_dir.join(ETAG_MANIFEST),
+                serde_json::to_string_pretty(&self.manifest)?.as_bytes(),
+            )?;
+            self.manifest_dirty = false;
+        }
+        if self.fingerprints_dirty {
+            write_atomic(
+                self.base_dir.join(FINGERPRINTS),
+                serde_json::to_string_pretty(&self.fingerprints)?.as_bytes(),
+            )?;
+            self.fingerprints_dirty = false;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    fn test_writer(html_dir: &path::Path, compress: bool) -> OutputWriter {
+        let cfg = test_config(serde_json::json!({
+            "html_dir": html_dir.to_string_lossy(),
+            "output_gzip": compress,
+            "output_brotli": compress,
+            "output_etag_manifest": true
+        }));
+        OutputWriter::new(&cfg)
+    }
+
+    #[test]
+    fn writes_pages_siblings_and_state() {
+        let html_dir = std::env::temp_dir().join(format!("urlharvest-output-test.{}", std::process::id()));
+        fs::create_dir_all(&html_dir).unwrap();
+        let page = html_dir.join("url.html");
+        let sibling = |suffix: &str| html_dir.join(format!("url.html.{suffix}"));
+
+        let mut output = test_writer(&html_dir, true);
+        assert!(output.write(&page, b"one").unwrap());
+        output.set_fingerprint(&page, "f1".to_owned());
+        let mut gunzipped = String::new();
+        flate2::read::GzDecoder::new(fs::File::open(sibling(GZIP_SUFFIX)).unwrap())
+            .read_to_string(&mut gunzipped)
+            .unwrap();
+        assert_eq!(gunzipped, "one");
+        let mut unbrotlied = String::new();
+        brotli::Decompressor::new(fs::File::open(sibling(BROTLI_SUFFIX)).unwrap(), BUF_SZ)
+            .read_to_string(&mut unbrotlied)
+            .unwrap();
+        assert_eq!(unbrotlied, "one");
+
+        // the same content leaves the page alone
+        let modified = fs::metadata(&page).unwrap().modified().unwrap();
+        assert!(!output.write(&page, b"one").unwrap());
+        assert_eq!(fs::metadata(&page).unwrap().modified().unwrap(), modified);
+
+        // a missing sibling is written again
+        fs::remove_file(sibling(GZIP_SUFFIX)).unwrap();
+        assert!(!output.write(&page, b"one").unwrap());
+        assert!(sibling(GZIP_SUFFIX).exists());
+
+        output.write_state().unwrap();
+        let manifest: BTreeMap<String, ManifestEntry> =
+            serde_json::from_slice(&fs::read(html_dir.join(ETAG_MANIFEST)).unwrap()).unwrap();
+        assert_eq!(manifest["url.html"].etag, content_etag(b"one"));
+        assert_eq!(manifest["url.html"].size, 3);
+
+        // the fingerprints survive a restart, the siblings go with their options
+        let mut output = test_writer(&html_dir, false);
+        assert!(output.is_current(&page, "f1"));
+        assert!(!output.is_current(&page, "f2"));
+        assert!(output.write(&page, b"two").unwrap());
+        assert!(!sibling(GZIP_SUFFIX).exists() && !sibling(BROTLI_SUFFIX).exists());
+
+        output.remove(&page).unwrap();
+        output.write_state().unwrap();
+        assert!(!page.exists());
+        assert!(!test_writer(&html_dir, false).is_current(&page, "f1"));
+        let _ = fs::remove_dir_all(&html_dir);
+    }
+}
+
+// EOF
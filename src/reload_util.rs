@@ -0,0 +1,79 @@
+// reload_util.rs
+
+use tokio::{
+    signal::unix::{SignalKind, signal},
+    sync::watch,
+};
+
+use crate::*;
+
+// Counts SIGHUPs, the daemons reload their config when it changes
+#[derive(Debug, Clone)]
+pub struct Reload {
+    rx: watch::Receiver<u64>,
+}
+
+impl Reload {
+    pub fn listen() -> anyhow::Result<Self> {
+        let mut sighup = signal(SignalKind::hangup())?;
+        let (tx, rx) = watch::channel(0u64);
+        tokio::spawn(async move {
+            while sighup.recv().await.is_some() {
+                tx.send_modify(|n| *n = n.wrapping_add(1));
+            }
+        });
+        Ok(Self { rx })
+    }
+
+    // Completes on the next SIGHUP, or at once if one has arrived since the last check
+    pub async fn requested(&mut self) {
+        // the sender lives as long as the process
+        let _ = self.rx.changed().await;
+    }
+
+    // Whether a SIGHUP has arrived since the last check, for loops that cannot wait on it
+    pub fn take(&mut self) -> bool {
+        let requested = self.rx.has_changed().unwrap_or(false);
+        self.rx.mark_unchanged();
+        requested
+    }
+}
+
+// Re-reads and checks the config for the daemon. On any problem the reload is rejected and the current config kept.
+// Updates the privacy settings the queries of the daemon filter on, the rest is up to the daemon.
+pub async fn reload_config(cfg: &ConfigCommon, daemon: &str, db: &DbCtx) -> Option<ConfigCommon> {
+    info!("SIGHUP received, reloading the config from {}", cfg.config_file);
+    let new_cfg = match cfg.reload() {
+        Ok(new_cfg) => new_cfg,
+        Err(e) => {
+            error!("Config reload rejected, keeping the current config: {e}");
+            return None;
+        }
+    };
+    let problems = check_cmd::check_reload(&new_cfg, daemon).await;
+    if !problems.is_empty() {
+        for problem in &problems {
+            error!("Config problem: {problem}");
+        }
+        error!(
+            "Config reload rejected with {} problem(s), keeping the current config",
+            problems.len()
+        );
+        return None;
+    }
+    for field in cfg.restart_needed(&new_cfg, daemon) {
+        warn!("{field} changed, restart {daemon} to take it into use");
+    }
+
+    let privacy = new_cfg.privacy();
+    if privacy != cfg.privacy()
+        && let Err(e) = db.set_privacy(&privacy).await
+    {
+        error!("Config reload rejected, could not update the privacy settings: {e}");
+        return None;
+    }
+    info!("Config reloaded");
+    Some(new_cfg)
+}
+
+// EOF
@@ -0,0 +1,357 @@
+// rule_util.rs
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::*;
+
+const APPLY_BATCH: usize = 4096;
+
+#[derive(Debug)]
+enum Matcher {
+    // lowercase domain, also matches the subdomains
+    Domain(String),
+    Regex(Regex),
+    // lowercase, IRC names are case insensitive
+    Nick(String),
+    Channel(String),
+    Network(String),
+    // legacy url_blacklist entry
+    Prefix(String),
+}
+
+struct RuleInput<'a> {
+    network: &'a str,
+    chan: &'a str,
+    nick: &'a str,
+    url: &'a str,
+    host: Option<&'a str>,
+}
+
+impl Matcher {
+    fn matches(&self, input: &RuleInput) -> bool {
+        match self {
+            Matcher::Domain(domain) => input.host.is_some_and(|host| {
+                host == domain || host.strip_suffix(domain.as_str()).is_some_and(|sub| sub.ends_with('.'))
+            }),
+            Matcher::Regex(re) => re.is_match(input.url),
+            Matcher::Nick(nick) => input.nick.to_lowercase() == *nick,
+            Matcher::Channel(chan) => input.chan.to_lowercase() == *chan,
+            Matcher::Network(network) => input.network.to_lowercase() == *network,
+            Matcher::Prefix(prefix) => input.url.starts_with(prefix),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Rule {
+    action: RuleAction,
+    matchers: Vec<Matcher>,
+    // for the logs, e.g. "#2 deny nick=linkbot"
+    desc: String,
+    hits: AtomicU64,
+}
+
+impl Rule {
+    fn new(action: RuleAction, matchers: Vec<(Matcher, String)>, name: &str) -> Self {
+        let mut desc = format!("{name} {action:?}").to_lowercase();
+        for (_, m) in &matchers {
+            desc.push(' ');
+            desc.push_str(m);
+        }
+        Self {
+            action,
+            matchers: matchers.into_iter().map(|(m, _)| m).collect(),
+            desc,
+            hits: AtomicU64::new(0),
+        }
+    }
+
+    fn has_network(&self) -> bool {
+        self.matchers.iter().any(|m| matches!(m, Matcher::Network(_)))
+    }
+}
+
+fn rule_host(url: &str) -> Option<String> {
+    url::Url::parse(url)
+        .ok()
+        .and_then(|u| u.host_str().map(|h| h.trim_end_matches('.').to_lowercase()))
+}
+
+// The url_rules followed by url_blacklist. The first matching rule decides,
+// URLs that no rule matches are allowed.
+#[derive(Debug)]
+pub struct RuleSet {
+    rules: Vec<Rule>,
+    network: String,
+}
+
+impl RuleSet {
+    pub fn new(configs: &[RuleConfig], blacklist: &[String], network: &str) -> anyhow::Result<Self> {
+        let mut rules = Vec::with_capacity(configs.len() + blacklist.len());
+        for (i, c) in configs.iter().enumerate() {
+            let name = format!("#{}", i + 1);
+            let mut matchers = Vec::new();
+            if let Some(domain) = &c.domain {
+                let domain = domain.trim_start_matches('.').to_lowercase();
+                matchers.push((Matcher::Domain(domain.clone()), format!("domain={domain}")));
+            }
+            if let Some(re) = &c.regex {
+                let regex = Regex::new(re).map_err(|e| anyhow!("url_rules {name}: {e}"))?;
+                matchers.push((Matcher::Regex(regex), format!("regex={re}")));
+            }
+            if let Some(nick) = &c.nick {
+                matchers.push((Matcher::Nick(nick.to_lowercase()), format!("nick={nick}")));
+            }
+            if let Some(chan) = &c.channel {
+                matchers.push((Matcher::Channel(chan.to_lowercase()), format!("channel={chan}")));
+            }
+            if let Some(network) = &c.network {
+                matchers.push((Matcher::Network(network.to_lowercase()), format!("network={network}")));
+            }
+            if matchers.is_empty() {
+                bail!("url_rules {name} has nothing to match");
+            }
+            rules.push(Rule::new(c.action, matchers, &name));
+        }
+        for prefix in blacklist {
+            rules.push(Rule::new(
+                RuleAction::Deny,
+                vec![(Matcher::Prefix(prefix.clone()), format!("prefix={prefix}"))],
+                "url_blacklist",
+            ));
+        }
+        Ok(Self {
+            rules,
+            network: network.to_string(),
+        })
+    }
+
+    pub fn check(&self, chan: &str, nick: &str, url: &str) -> RuleAction {
+        let host = rule_host(url);
+        let input = RuleInput {
+            network: &self.network,
+            chan,
+            nick,
+            url,
+            host: host.as_deref(),
+        };
+        match self
+            .rules
+            .iter()
+            .find(|rule| rule.matchers.iter().all(|m| m.matches(&input)))
+        {
+            Some(rule) => {
+                rule.hits.fetch_add(1, Ordering::Relaxed);
+                metrics()
+                    .rule_hits
+                    .with_label_values(&[rule.desc.as_str(), rule.action.as_str()])
+                    .inc();
+                debug!("URL rule {} matched {url}", rule.desc);
+                rule.action
+            }
+            None => RuleAction::Allow,
+        }
+    }
+
+    // The first rule that matches a stored sighting, without counting it as a hit.
+    // The sightings have no network, so network rules match any and leave the sighting undecided.
+    fn stored_match(&self, u: &DbUrl) -> Option<&Rule> {
+        let host = rule_host(&u.url);
+        let input = RuleInput {
+            network: "",
+            chan: &u.channel,
+            nick: &u.nick,
+            url: &u.url,
+            host: host.as_deref(),
+        };
+        self.rules.iter().find(|rule| {
+            rule.matchers
+                .iter()
+                .all(|m| matches!(m, Matcher::Network(_)) || m.matches(&input))
+        })
+    }
+
+    pub fn allows(&self, chan: &str, nick: &str, url: &str) -> bool {
+        self.check(chan, nick, url) == RuleAction::Allow
+    }
+
+    // How many times each rule has decided since startup
+    pub fn hits(&self) -> Vec<(&str, u64)> {
+        self.rules
+            .iter()
+            .map(|r| (r.desc.as_str(), r.hits.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    pub fn log_hits(&self) {
+        for (desc, hits) in self.hits() {
+            info!("URL rule {desc}: {hits} hit(s)");
+        }
+    }
+
+    // Removes the stored sightings the rules deny, keeping them restorable, or with dry_run only lists them.
+    // Returns the number of sightings denied.
+    pub async fn apply<R: UrlRepo>(&self, repo: &R, dry_run: bool) -> anyhow::Result<u64> {
+        let mut after_id = 0;
+        let mut n_checked = 0;
+        let mut n_denied = 0;
+        let mut n_undecided = 0;
+        loop {
+            let rows = repo.sightings(after_id, APPLY_BATCH).await?;
+            let Some(last) = rows.last() else {
+                break;
+            };
+            after_id = last.id;
+            n_checked += rows.len();
+            let mut denied = Vec::new();
+            for u in &rows {
+                match self.stored_match(u) {
+                    Some(rule) if rule.has_network() => n_undecided += 1,
+                    Some(rule) if rule.action == RuleAction::Deny => {
+                        if dry_run {
+                            println!(
+                                "{:>8}  {}  {} <{}> {}  ({})",
+                                u.id,
+                                u.seen.ts_short_y(),
+                                u.channel,
+                                u.nick,
+                                u.url,
+                                rule.desc
+                            );
+                        }
+                        denied.push(u.id);
+                    }
+                    _ => {}
+                }
+            }
+            n_denied += match dry_run {
+                true => denied.len() as u64,
+                false => repo.remove_sightings(&denied).await?,
+            };
+        }
+        if n_undecided > 0 {
+            warn!("Kept {n_undecided} sighting(s) a network rule might deny, the stored sightings have no network");
+        }
+        match dry_run {
+            true => println!("{n_denied} of {n_checked} sighting(s) would be removed"),
+            false => info!(
+                "Checked {n_checked} sighting(s) against the URL rules, removed {n_denied}, `restore <id>` brings them back"
+            ),
+        }
+        Ok(n_denied)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(action: RuleAction) -> RuleConfig {
+        RuleConfig {
+            action,
+            domain: None,
+            regex: None,
+            nick: None,
+            channel: None,
+            network: None,
+        }
+    }
+
+    fn rules() -> RuleSet {
+        let configs = [
+            RuleConfig {
+                domain: Some("example.com".to_owned()),
+                channel: Some("#Public".to_owned()),
+                ..rule(RuleAction::Allow)
+            },
+            RuleConfig {
+                domain: Some("example.com".to_owned()),
+                ..rule(RuleAction::Deny)
+            },
+            RuleConfig {
+                nick: Some("LinkBot".to_owned()),
+                ..rule(RuleAction::Deny)
+            },
+            RuleConfig {
+                regex: Some(r"\.onion(/|$)".to_owned()),
+                ..rule(RuleAction::Deny)
+            },
+            RuleConfig {
+                channel: Some("#private".to_owned()),
+                network: Some("ircnet".to_owned()),
+                ..rule(RuleAction::Deny)
+            },
+        ];
+        RuleSet::new(&configs, &["https://znc.in".to_owned()], "IRCnet").unwrap()
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let rules = rules();
+        let cases = [
+            ("#chan", "a", "https://example.com/x", RuleAction::Deny),
+            ("#chan", "a", "https://www.EXAMPLE.com/x", RuleAction::Deny),
+            ("#chan", "a", "https://notexample.com/x", RuleAction::Allow),
+            ("#public", "a", "https://sub.example.com/x", RuleAction::Allow),
+            ("#chan", "linkbot", "https://rust-lang.org", RuleAction::Deny),
+            ("#chan", "a", "http://abc.onion/page", RuleAction::Deny),
+            ("#Private", "a", "https://rust-lang.org", RuleAction::Deny),
+            ("#chan", "a", "https://znc.in/x", RuleAction::Deny),
+            ("#chan", "a", "https://rust-lang.org", RuleAction::Allow),
+        ];
+        for (chan, nick, url, action) in cases {
+            assert_eq!(rules.check(chan, nick, url), action, "{chan} {nick} {url}");
+        }
+        let hits = rules.hits().into_iter().map(|(_, n)| n).collect::<Vec<_>>();
+        assert_eq!(hits, [1, 2, 1, 1, 1, 1]);
+        assert_eq!(rules.hits()[0].0, "#1 allow domain=example.com channel=#Public");
+    }
+
+    #[test]
+    fn network_rules_need_the_network() {
+        let configs = [RuleConfig {
+            channel: Some("#private".to_owned()),
+            network: Some("ircnet".to_owned()),
+            ..rule(RuleAction::Deny)
+        }];
+        let rules = RuleSet::new(&configs, &[], "libera").unwrap();
+        assert!(rules.allows("#private", "a", "https://rust-lang.org"));
+    }
+
+    #[test]
+    fn empty_rule_is_an_error() {
+        assert!(RuleSet::new(&[rule(RuleAction::Deny)], &[], "").is_err());
+    }
+
+    #[tokio::test]
+    async fn apply_removes_denied_sightings() {
+        let repo = MemRepo::new();
+        let sighting = |nick, url| test_sighting(100, "#chan", nick, url);
+        repo.add_sightings(&[
+            sighting("a", "https://example.com/1"),
+            sighting("a", "https://rust-lang.org"),
+            sighting("linkbot", "https://rust-lang.org"),
+            // the stored sightings have no network for the #private rule to check
+            test_sighting(100, "#private", "a", "https://rust-lang.org"),
+        ])
+        .await
+        .unwrap();
+        let ids = async || {
+            let left = repo.sightings(0, 10).await.unwrap();
+            left.iter().map(|u| u.id).collect::<Vec<_>>()
+        };
+
+        let rules = rules();
+        assert_eq!(rules.apply(&repo, true).await.unwrap(), 2);
+        assert_eq!(ids().await, [1, 2, 3, 4]);
+        assert_eq!(rules.apply(&repo, false).await.unwrap(), 2);
+        assert_eq!(ids().await, [2, 4]);
+        // the live hit counters only count the harvested lines
+        assert!(rules.hits().iter().all(|(_, n)| *n == 0));
+
+        assert_eq!(repo.restore_url(3).await.unwrap(), 1);
+        assert_eq!(ids().await, [2, 3, 4]);
+    }
+}
+
+// EOF
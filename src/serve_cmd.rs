@@ -0,0 +1,460 @@
+// serve_cmd.rs
+
+use std::sync::{
+    Arc, RwLock,
+    atomic::{AtomicU64, Ordering},
+};
+
+use axum::{
+    body::Body,
+    extract::{MatchedPath, Query, Request, State},
+    http::{StatusCode, header},
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Response},
+    routing::*,
+};
+// use axum_macros::debug_handler;
+use handlebars::{Handlebars, to_json};
+use itertools::Itertools;
+
+use crate::*;
+
+// the name in the config checks, the status endpoints are served on search_listen
+pub const DAEMON: &str = "urllog_actions";
+const TPL_INDEX: &str = "index";
+const TPL_RESULT_HEADER: &str = "result_header";
+const TPL_RESULT_ROW: &str = "result_row";
+const TPL_RESULT_FOOTER: &str = "result_footer";
+
+// built-in templates used when the configured files do not exist
+const DEFAULT_TPL_INDEX: &str = "search_index.html.hbs";
+const DEFAULT_TPL_RESULT_HEADER: &str = "search_result_header.html.hbs";
+const DEFAULT_TPL_RESULT_ROW: &str = "search_result_row.html.hbs";
+const DEFAULT_TPL_RESULT_FOOTER: &str = "search_result_footer.html.hbs";
+
+const DEFAULT_REPLY_CAP: usize = 65536;
+// default for the [serve] setting, seconds sent with the 503 responses
+const RETRY_AFTER_BUSY: u64 = 5;
+// the errors turn into responses without the state at hand
+static RETRY_AFTER: AtomicU64 = AtomicU64::new(RETRY_AFTER_BUSY);
+const RE_SEARCH: &str = r"^[-_\.:;/0-9a-zA-Z\?\*\(\)\[\]\{\}\|\\ ]*$";
+
+struct Templates<'a> {
+    index_html: String,
+    hb_reg: Handlebars<'a>,
+}
+
+struct MyState<'a> {
+    templates: RwLock<Arc<Templates<'a>>>,
+    re_search: Regex,
+    db: DbCtx,
+}
+
+impl<'a> MyState<'a> {
+    // Requests hold on to the templates they started with, even if a reload happens meanwhile
+    fn templates(&self) -> Arc<Templates<'a>> {
+        match self.templates.read() {
+            Ok(t) => t.clone(),
+            Err(e) => e.into_inner().clone(),
+        }
+    }
+
+    fn set_templates(&self, templates: Templates<'a>) {
+        match self.templates.write() {
+            Ok(mut t) => *t = Arc::new(templates),
+            Err(e) => *e.into_inner() = Arc::new(templates),
+        }
+    }
+}
+
+enum AppError {
+    // all pooled database connections are in use
+    Busy,
+    Params(String),
+    Render(handlebars::RenderError),
+    Sqlx(sqlx::Error),
+    Stream(std::io::Error),
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response<Body> {
+        let (status, message) = match self {
+            AppError::Busy => {
+                let retry_after = RETRY_AFTER.load(Ordering::Relaxed).to_string();
+                return (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    [
+                        (header::CACHE_CONTROL, "no-store"),
+                        (header::RETRY_AFTER, retry_after.as_str()),
+                    ],
+                    "Server busy, please try again shortly",
+                )
+                    .into_response();
+            }
+            AppError::Params(msg) => (StatusCode::BAD_REQUEST, msg),
+            AppError::Render(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Template render error: {e}")),
+            AppError::Sqlx(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("SQLx error: {e}")),
+            AppError::Stream(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Iterator error: {e}")),
+        };
+        (status, [(header::CACHE_CONTROL, "no-store")], message).into_response()
+    }
+}
+impl From<handlebars::RenderError> for AppError {
+    fn from(e: handlebars::RenderError) -> Self {
+        Self::Render(e)
+    }
+}
+impl From<sqlx::Error> for AppError {
+    fn from(e: sqlx::Error) -> Self {
+        match e {
+            sqlx::Error::PoolTimedOut => {
+                warn!("Database pool exhausted");
+                Self::Busy
+            }
+            e => Self::Sqlx(e),
+        }
+    }
+}
+impl From<std::io::Error> for AppError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Stream(e)
+    }
+}
+
+// Serves the search UI and the URL administration actions on search_listen
+pub async fn run(cfg: &ConfigCommon) -> anyhow::Result<()> {
+    check_cmd::startup(cfg, DAEMON).await?;
+    let db = start_db(cfg).await?;
+    let shutdown = Shutdown::listen(cfg)?;
+    start_watchdog(cfg);
+
+    let templates = load_templates(cfg)?;
+    RETRY_AFTER.store(cfg.serve.retry_after.unwrap_or(RETRY_AFTER_BUSY), Ordering::Relaxed);
+
+    // precompile this regex
+    let re_search = Regex::new(RE_SEARCH)?;
+
+    let server_addr: net::SocketAddr = cfg.search_listen;
+
+    let my_state = Arc::new(MyState {
+        templates: RwLock::new(Arc::new(templates)),
+        re_search,
+        db,
+    });
+    tokio::spawn(reload_on_sighup(cfg.clone(), Reload::listen()?, my_state.clone()));
+    tokio::spawn(keep_alive());
+
+    let mut app = Router::new()
+        .route("/", get(get_index).options(options))
+        .route("/search", get(search))
+        .route("/remove_url", get(remove_url))
+        .route("/remove_meta", get(remove_meta))
+        .route(HEALTH_PATH, get(get_health))
+        .route(READY_PATH, get(get_ready));
+    if cfg.metrics_enabled {
+        app = app
+            .route(METRICS_PATH, get(get_metrics))
+            .route_layer(middleware::from_fn(track_request));
+    }
+    let app = app.with_state(my_state);
+
+    let listener = tokio::net::TcpListener::bind(&server_addr).await?;
+    info!("API server listening to {server_addr}");
+    health().set_ready();
+    // in-flight requests are answered before returning
+    axum::serve(listener, app.into_make_service())
+        .with_graceful_shutdown(async move { shutdown.requested().await })
+        .await?;
+    Ok(())
+}
+
+// The web server has no main loop of its own, the runtime running timers is the sign of life
+async fn keep_alive() {
+    loop {
+        health().beat();
+        sleep(Duration::new(HEALTH_BEAT_INTERVAL, 0)).await;
+    }
+}
+
+// Ready when the templates are loaded and the database answers
+async fn get_ready(State(state): State<Arc<MyState<'_>>>) -> (StatusCode, String) {
+    if !health().is_ready() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "starting up\n".to_string());
+    }
+    let templates = state.templates();
+    if let Some(tpl) = [TPL_RESULT_HEADER, TPL_RESULT_ROW, TPL_RESULT_FOOTER]
+        .into_iter()
+        .find(|t| !templates.hb_reg.has_template(t))
+    {
+        return (StatusCode::SERVICE_UNAVAILABLE, format!("template {tpl} not loaded\n"));
+    }
+    match db_ping(&state.db.dbc).await {
+        Ok(()) => (StatusCode::OK, "ready\n".to_string()),
+        Err(e) => (StatusCode::SERVICE_UNAVAILABLE, format!("database: {e}\n")),
+    }
+}
+
+// Request latency by route, for the metrics
+async fn track_request(req: Request, next: Next) -> Response {
+    let path = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| req.uri().path().to_string(), |p| p.as_str().to_string());
+    let start = time::Instant::now();
+    let resp = next.run(req).await;
+    metrics()
+        .http_request_seconds
+        .with_label_values(&[path.as_str(), resp.status().as_str()])
+        .observe(start.elapsed().as_secs_f64());
+    resp
+}
+
+// Loads and trial renders the templates like a start would, for the config check
+pub fn check_templates(cfg: &ConfigCommon) -> anyhow::Result<()> {
+    load_templates(cfg).map(|_| ())
+}
+
+fn load_templates(cfg: &ConfigCommon) -> anyhow::Result<Templates<'static>> {
+    // Now it's time for some iterator porn.
+    let (
+        tpl_path_search_index,
+        tpl_path_search_result_header,
+        tpl_path_search_result_row,
+        tpl_path_search_result_footer,
+    ) = [
+        &cfg.tpl_search_index,
+        &cfg.tpl_search_result_header,
+        &cfg.tpl_search_result_row,
+        &cfg.tpl_search_result_footer,
+    ]
+    .iter()
+    .map(|t| {
+        // template names are relative to template_dir
+        // hence we construct full paths here
+        path::Path::new(&cfg.template_dir).join(*t)
+    })
+    .collect_tuple()
+    .ok_or_else(|| anyhow!("Template iteration failed"))?;
+
+    // Create Handlebars registry
+    let mut hb_reg = Handlebars::new();
+
+    // We handle html escaping ourselves
+    hb_reg.register_escape_fn(handlebars::no_escape);
+
+    // We render index html statically and save it
+    register_template(&mut hb_reg, TPL_INDEX, &tpl_path_search_index, DEFAULT_TPL_INDEX)?;
+    let mut tpl_data = serde_json::value::Map::new();
+    tpl_data.insert("cmd_search".into(), to_json("search"));
+    let index_html = hb_reg.render(TPL_INDEX, &tpl_data)?;
+
+    // Register other templates
+    register_template(
+        &mut hb_reg,
+        TPL_RESULT_HEADER,
+        &tpl_path_search_result_header,
+        DEFAULT_TPL_RESULT_HEADER,
+    )?;
+    register_template(
+        &mut hb_reg,
+        TPL_RESULT_ROW,
+        &tpl_path_search_result_row,
+        DEFAULT_TPL_RESULT_ROW,
+    )?;
+    register_template(
+        &mut hb_reg,
+        TPL_RESULT_FOOTER,
+        &tpl_path_search_result_footer,
+        DEFAULT_TPL_RESULT_FOOTER,
+    )?;
+
+    // Trial render the rest too, so that broken templates are caught before use
+    let tpl_data_empty = serde_json::value::Map::new();
+    hb_reg.render(TPL_RESULT_HEADER, &tpl_data_empty)?;
+    hb_reg.render(TPL_RESULT_ROW, &row_data(&sample_row()))?;
+    hb_reg.render(TPL_RESULT_FOOTER, &tpl_data_empty)?;
+
+    Ok(Templates { index_html, hb_reg })
+}
+
+async fn reload_on_sighup(mut cfg: ConfigCommon, mut reload: Reload, state: Arc<MyState<'static>>) {
+    loop {
+        reload.requested().await;
+        let Some(new_cfg) = reload_config(&cfg, DAEMON, &state.db).await else {
+            continue;
+        };
+        // the config and the templates change together or not at all
+        match load_templates(&new_cfg) {
+            Ok(templates) => {
+                state.set_templates(templates);
+                RETRY_AFTER.store(new_cfg.serve.retry_after.unwrap_or(RETRY_AFTER_BUSY), Ordering::Relaxed);
+                cfg = new_cfg;
+            }
+            Err(e) => error!("Template reload failed, keeping the current config and templates: {e}"),
+        }
+    }
+}
+
+fn register_template(
+    hb_reg: &mut Handlebars,
+    name: &str,
+    tpl_path: &path::Path,
+    default_name: &str,
+) -> anyhow::Result<()> {
+    if tpl_path.exists() {
+        hb_reg.register_template_file(name, tpl_path)?;
+    } else {
+        info!("Template {tpl_path:?} not found, using the built-in default");
+        let content =
+            default_hbs_template(default_name).ok_or_else(|| anyhow!("No built-in template {default_name}"))?;
+        hb_reg.register_template_string(name, content)?;
+    }
+    Ok(())
+}
+
+async fn options<'a>(State(_state): State<Arc<MyState<'a>>>) -> Response<Body> {
+    (
+        StatusCode::OK,
+        [
+            (header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"),
+            (header::ACCESS_CONTROL_ALLOW_METHODS, "get,post"),
+            (header::ACCESS_CONTROL_ALLOW_HEADERS, "content-type"),
+        ],
+    )
+        .into_response()
+}
+
+async fn get_index<'a>(State(state): State<Arc<MyState<'a>>>) -> Response<Body> {
+    (StatusCode::OK, Html(state.templates().index_html.clone())).into_response()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchParam {
+    chan: String,
+    nick: String,
+    url: String,
+    title: String,
+    // older search pages do not send these
+    #[serde(default)]
+    msg: String,
+    #[serde(default)]
+    kind: String,
+}
+
+fn row_data(row: &DbUrlRow) -> serde_json::value::Map<String, serde_json::Value> {
+    let mut tpl_data_row = serde_json::value::Map::new();
+    [
+        ("id", row.id.to_string()),
+        ("first_seen", row.seen_first.ts_short_y()),
+        ("last_seen", row.seen_last.ts_short()),
+        ("num_seen", row.seen_cnt.to_string()),
+        ("chans", row.channel.esc_et_lt_gt().sort_dedup_br()),
+        ("nicks", row.nick.esc_et_lt_gt().sort_dedup_br()),
+        ("url", row.url.esc_quot()),
+        ("title", row.title.esc_et_lt_gt()),
+        ("msg", row.msg.esc_et_lt_gt()),
+        ("kind", row.kind.esc_et_lt_gt()),
+    ]
+    .iter()
+    .for_each(|(k, v)| {
+        tpl_data_row.insert(k.to_string(), to_json(v));
+    });
+    tpl_data_row
+}
+
+fn sample_row() -> DbUrlRow {
+    DbUrlRow {
+        id: 1,
+        seen_first: 1,
+        seen_last: 2,
+        seen_cnt: 2,
+        channel: "#test".to_owned(),
+        nick: "test".to_owned(),
+        url: "https://example.com".to_owned(),
+        title: "Example".to_owned(),
+        msg: "look at https://example.com".to_owned(),
+        kind: "privmsg".to_owned(),
+    }
+}
+
+async fn search<'a>(
+    State(state): State<Arc<MyState<'a>>>,
+    Query(params): Query<SearchParam>,
+) -> Result<Response, AppError> {
+    info!("search({params:?})");
+
+    let re = &state.re_search;
+    if !(re.is_match(&params.chan)
+        && re.is_match(&params.nick)
+        && re.is_match(&params.url)
+        && re.is_match(&params.title)
+        && re.is_match(&params.msg)
+        && re.is_match(&params.kind))
+    {
+        return Err(AppError::Params("Invalid search parameters".to_string()));
+    }
+
+    let query = SearchQuery {
+        chan: params.chan.sql_search(),
+        nick: params.nick.sql_search(),
+        url: params.url.sql_search(),
+        title: params.title.sql_search(),
+        msg: params.msg.sql_search(),
+        kind: params.kind.sql_search(),
+    };
+    info!(
+        "Search {} {} {} {} {} {}",
+        query.chan, query.nick, query.url, query.title, query.msg, query.kind
+    );
+
+    let templates = state.templates();
+    let tpl_data_empty = serde_json::value::Map::new();
+    let html_header = templates.hb_reg.render(TPL_RESULT_HEADER, &tpl_data_empty)?;
+    let html_footer = templates.hb_reg.render(TPL_RESULT_FOOTER, &tpl_data_empty)?;
+
+    let mut html = String::with_capacity(DEFAULT_REPLY_CAP);
+    html.push_str(&html_header);
+
+    for row in state.db.search(&query).await? {
+        // debug!("Result row:\n{row:#?}");
+        html.push_str(&templates.hb_reg.render(TPL_RESULT_ROW, &row_data(&row))?);
+    }
+
+    html.push_str(&html_footer);
+    Ok(([(header::CACHE_CONTROL, "no-store")], Html(html)).into_response())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RemoveParam {
+    id: String,
+}
+async fn remove_url<'a>(
+    State(state): State<Arc<MyState<'a>>>,
+    Query(params): Query<RemoveParam>,
+) -> Result<Response<Body>, AppError> {
+    info!("remove_url({params:?})");
+    let id = params.id.parse::<i32>().unwrap_or_default();
+    info!("Remove url id {id}");
+
+    let n_rows = state.db.remove_url(id).await?;
+
+    let msg = format!("Removed #{n_rows}");
+    info!("{msg}");
+    Ok(([(header::CACHE_CONTROL, "no-store")], msg).into_response())
+}
+
+async fn remove_meta<'a>(
+    State(state): State<Arc<MyState<'a>>>,
+    Query(params): Query<RemoveParam>,
+) -> Result<Response<Body>, AppError> {
+    info!("remove_meta({params:?})");
+    let id = params.id.parse::<i32>().unwrap_or_default();
+    info!("Remove meta id {id}");
+
+    let n_rows = state.db.remove_meta(id).await?;
+
+    let msg = format!("Refreshing (#{n_rows})");
+    info!("{msg}");
+    Ok(([(header::CACHE_CONTROL, "no-store")], msg).into_response())
+}
+// EOF
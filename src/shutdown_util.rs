@@ -0,0 +1,59 @@
+// shutdown_util.rs
+
+use sd_notify::NotifyState;
+use tokio::{
+    signal::unix::{SignalKind, signal},
+    sync::watch,
+};
+
+use crate::*;
+
+// seconds from the first SIGTERM or SIGINT until the process exits anyway
+const SHUTDOWN_TIMEOUT: u64 = 30;
+
+// Set once SIGTERM or SIGINT arrives, the daemons then finish the work at hand and return from main
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    rx: watch::Receiver<bool>,
+}
+
+impl Shutdown {
+    // A second signal or shutdown_timeout seconds after the first one end the process at once
+    pub fn listen(cfg: &ConfigCommon) -> anyhow::Result<Self> {
+        let timeout = cfg.shutdown_timeout.unwrap_or(SHUTDOWN_TIMEOUT);
+        let mut sigterm = signal(SignalKind::terminate())?;
+        let mut sigint = signal(SignalKind::interrupt())?;
+        let (tx, rx) = watch::channel(false);
+        tokio::spawn(async move {
+            let sig = tokio::select! {
+                _ = sigterm.recv() => "SIGTERM",
+                _ = sigint.recv() => "SIGINT",
+            };
+            info!("{sig} received, shutting down");
+            if let Err(e) = sd_notify::notify(&[NotifyState::Stopping]) {
+                warn!("sd_notify STOPPING failed: {e}");
+            }
+            tx.send_replace(true);
+            tokio::select! {
+                _ = sleep(Duration::new(timeout, 0)) => error!("Shutdown did not finish in {timeout} s, exiting"),
+                _ = sigterm.recv() => warn!("SIGTERM received again, exiting"),
+                _ = sigint.recv() => warn!("SIGINT received again, exiting"),
+            }
+            std::process::exit(1);
+        });
+        Ok(Self { rx })
+    }
+
+    pub fn is_requested(&self) -> bool {
+        *self.rx.borrow()
+    }
+
+    // Completes once shutdown has been requested
+    pub async fn requested(&self) {
+        let mut rx = self.rx.clone();
+        // the sender lives as long as the process
+        let _ = rx.wait_for(|requested| *requested).await;
+    }
+}
+
+// EOF
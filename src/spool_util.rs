@@ -0,0 +1,209 @@
+// spool_util.rs
+
+use std::io::Write;
+
+use crate::*;
+
+pub const DEFAULT_SPOOL_FILE: &str = "$HOME/urlharvest/spool.ndjson";
+const REPLAY_BATCH: usize = 1024;
+
+// Durable queue of sightings that could not be written to the database.
+// One JSON object per line, appended in the order the sightings were seen.
+#[derive(Debug)]
+pub struct Spool {
+    path: path::PathBuf,
+    depth: usize,
+}
+
+impl Spool {
+    pub fn open<P: AsRef<path::Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let depth = match fs::File::open(&path) {
+            Ok(f) => io::BufReader::new(f).lines().count(),
+            Err(_) => 0,
+        };
+        let spool = Self { path, depth };
+        if spool.depth > 0 {
+            spool.log_depth();
+        }
+        Ok(spool)
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    fn log_depth(&self) {
+        metrics().spool_depth.set(self.depth as i64);
+        warn!("Spool depth: {} sighting(s) waiting in {:?}", self.depth, self.path);
+    }
+
+    pub fn append(&mut self, urls: &[UrlCtx]) -> anyhow::Result<()> {
+        if urls.is_empty() {
+            return Ok(());
+        }
+        let buf = spool_lines(urls)?;
+        let mut f = fs::OpenOptions::new().create(true).append(true).open(&self.path)?;
+        f.write_all(&buf)?;
+        // a sighting is only safe once it is on disk
+        f.sync_data()?;
+        self.depth += urls.len();
+        self.log_depth();
+        Ok(())
+    }
+
+    // Writes the spooled sightings to the database in order.
+    // Whatever could not be written stays in the spool for the next attempt.
+    pub async fn replay<R: UrlRepo>(&mut self, repo: &R) -> anyhow::Result<u64> {
+        if self.depth == 0 {
+            return Ok(0);
+        }
+        let mut urls = Vec::with_capacity(self.depth);
+        for (i, line) in io::BufReader::new(fs::File::open(&self.path)?).lines().enumerate() {
+            let line = line?;
+            match serde_json::from_str::<UrlCtx>(&line) {
+                Ok(url) => urls.push(url),
+                // e.g. a partial line after a crash, nothing we can do about it
+                Err(e) => error!("Skipping unreadable spool line {}: {e}", i + 1),
+            }
+        }
+
+        if urls.len() < self.depth {
+            self.rewrite(&urls)?;
+        }
+
+        let mut n_rows = 0;
+        let mut rest = urls.as_slice();
+        while !rest.is_empty() {
+            let (batch, tail) = rest.split_at(rest.len().min(REPLAY_BATCH));
+            let n = match repo.add_sightings(batch).await {
+                Ok(n) => n,
+                Err(e) => {
+                    self.log_depth();
+                    bail!("spool replay stopped: {e}");
+                }
+            };
+            metrics().urls_inserted.inc_by(n);
+            n_rows += n;
+            rest = tail;
+            // right after the commit, so that a crash can only replay a single batch again
+            self.rewrite(rest)?;
+        }
+        info!("Spool replayed, {n_rows} row(s) inserted");
+        Ok(n_rows)
+    }
+
+    // Leaves only the given sightings in the spool
+    fn rewrite(&mut self, rest: &[UrlCtx]) -> anyhow::Result<()> {
+        if rest.is_empty() {
+            match fs::remove_file(&self.path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        } else {
+            write_atomic(&self.path, &spool_lines(rest)?)?;
+        }
+        self.depth = rest.len();
+        metrics().spool_depth.set(self.depth as i64);
+        Ok(())
+    }
+}
+
+fn spool_lines(urls: &[UrlCtx]) -> anyhow::Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(urls.len() * 128);
+    for url in urls {
+        serde_json::to_writer(&mut buf, url)?;
+        buf.push(b'\n');
+    }
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sightings(n: usize) -> Vec<UrlCtx> {
+        (0..n)
+            .map(|i| test_sighting(i as i64, "#42", "a", &format!("https://{i}.example")))
+            .collect()
+    }
+
+    fn spool_path(name: &str) -> path::PathBuf {
+        std::env::temp_dir().join(format!("urlharvest-spool-test.{}/{name}.ndjson", std::process::id()))
+    }
+
+    async fn seen(repo: &MemRepo) -> Vec<i64> {
+        repo.sightings(0, usize::MAX)
+            .await
+            .unwrap()
+            .iter()
+            .map(|u| u.seen)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn replays_and_removes_the_spool() {
+        let path = spool_path("replay");
+        let mut spool = Spool::open(&path).unwrap();
+        spool.append(&sightings(2)).unwrap();
+        spool.append(&sightings(3)[2..]).unwrap();
+        assert_eq!(Spool::open(&path).unwrap().depth(), 3);
+
+        let repo = MemRepo::new();
+        assert_eq!(spool.replay(&repo).await.unwrap(), 3);
+        assert_eq!(seen(&repo).await, [0, 1, 2]);
+        assert_eq!(spool.depth(), 0);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn keeps_the_rest_in_order_after_a_failure() {
+        let path = spool_path("partial");
+        let mut spool = Spool::open(&path).unwrap();
+        let urls = sightings(REPLAY_BATCH + 10);
+        spool.append(&urls).unwrap();
+
+        let repo = MemRepo::new();
+        repo.fail_sighting_writes_after(1);
+        assert!(spool.replay(&repo).await.is_err());
+        assert_eq!(seen(&repo).await.len(), REPLAY_BATCH);
+        assert_eq!(spool.depth(), 10);
+
+        // the next start picks up where the replay stopped
+        let mut spool = Spool::open(&path).unwrap();
+        assert_eq!(spool.depth(), 10);
+        let repo = MemRepo::new();
+        spool.replay(&repo).await.unwrap();
+        assert_eq!(
+            seen(&repo).await,
+            (REPLAY_BATCH as i64..REPLAY_BATCH as i64 + 10).collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn skips_unreadable_lines() {
+        let path = spool_path("unreadable");
+        let mut spool = Spool::open(&path).unwrap();
+        spool.append(&sightings(1)).unwrap();
+        // e.g. a partial line after a crash
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"ts\":1,\"ch\n")
+            .unwrap();
+        spool.append(&sightings(3)[2..]).unwrap();
+
+        let mut spool = Spool::open(&path).unwrap();
+        assert_eq!(spool.depth(), 3);
+        let repo = MemRepo::new();
+        assert_eq!(spool.replay(&repo).await.unwrap(), 2);
+        assert_eq!(seen(&repo).await, [0, 2]);
+        assert!(!path.exists());
+    }
+}
+
+// EOF
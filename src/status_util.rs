@@ -0,0 +1,200 @@
+// status_util.rs
+
+use std::sync::{
+    LazyLock,
+    atomic::{AtomicBool, AtomicI64, Ordering},
+};
+
+use axum::{http::StatusCode, routing::get};
+use sd_notify::NotifyState;
+use sqlx::AnyPool;
+
+use crate::*;
+
+pub const HEALTH_PATH: &str = "/healthz";
+pub const READY_PATH: &str = "/readyz";
+// seconds without a sign of life from the main loop before the daemon counts as stuck
+const HEALTH_STALL_TIMEOUT: u64 = 600;
+// seconds between the signs of life of an idle main loop
+pub const HEALTH_BEAT_INTERVAL: u64 = 30;
+// seconds to wait for the database listener to answer a probe
+const LISTENER_PROBE_TIMEOUT: u64 = 10;
+
+// Liveness of the main loop and readiness of the daemon, for /healthz, /readyz and the systemd watchdog
+pub struct Health {
+    ready: AtomicBool,
+    // unix time of the last sign of life from the main loop
+    last_beat: AtomicI64,
+    stall_timeout: AtomicI64,
+}
+
+static HEALTH: LazyLock<Health> = LazyLock::new(|| Health {
+    ready: AtomicBool::new(false),
+    last_beat: AtomicI64::new(Utc::now().timestamp()),
+    stall_timeout: AtomicI64::new(HEALTH_STALL_TIMEOUT as i64),
+});
+
+pub fn health() -> &'static Health {
+    &HEALTH
+}
+
+impl Health {
+    pub fn beat(&self) {
+        self.last_beat.store(Utc::now().timestamp(), Ordering::Relaxed);
+    }
+
+    // Seconds since the last sign of life, when that is longer than the stall timeout
+    pub fn stalled(&self) -> Option<i64> {
+        let silent = Utc::now().timestamp() - self.last_beat.load(Ordering::Relaxed);
+        (silent > self.stall_timeout.load(Ordering::Relaxed)).then_some(silent)
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Relaxed)
+    }
+
+    // Startup is done, tells systemd too when running as a Type=notify service
+    pub fn set_ready(&self) {
+        self.beat();
+        self.ready.store(true, Ordering::Relaxed);
+        if let Err(e) = sd_notify::notify(&[NotifyState::Ready]) {
+            warn!("sd_notify READY failed: {e}");
+        }
+    }
+}
+
+// Serves /healthz and /readyz, plus /metrics when metrics_enabled is set, on the status_listen address
+// of the daemon, and feeds the systemd watchdog while the main loop is alive.
+// urllog_actions has a web server of its own and adds the routes there instead.
+pub fn start_status(cfg: &ConfigCommon, name: &str, db: &DbCtx) {
+    start_watchdog(cfg);
+    let Some(addr) = cfg.status_listen.get(name).copied() else {
+        if cfg.metrics_enabled {
+            warn!("Metrics enabled but no status_listen address for {name}");
+        }
+        return;
+    };
+    let pool = db.dbc.clone();
+    let mut app = axum::Router::new()
+        .route(HEALTH_PATH, get(get_health))
+        .route(READY_PATH, get(move || get_ready(pool.clone())));
+    if cfg.metrics_enabled {
+        app = app.route(METRICS_PATH, get(get_metrics));
+    }
+    tokio::spawn(async move {
+        let res = match tokio::net::TcpListener::bind(addr).await {
+            Ok(listener) => {
+                info!("Status server listening to {addr}");
+                axum::serve(listener, app.into_make_service()).await
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res {
+            error!("Status server on {addr} failed: {e}");
+        }
+    });
+}
+
+// Takes health_stall_timeout into use and starts feeding the systemd watchdog when it is enabled
+pub fn start_watchdog(cfg: &ConfigCommon) {
+    let stall_timeout = cfg.health_stall_timeout.unwrap_or(HEALTH_STALL_TIMEOUT);
+    health().stall_timeout.store(stall_timeout as i64, Ordering::Relaxed);
+    health().beat();
+    if let Some(interval) = sd_notify::watchdog_enabled() {
+        info!("Feeding the systemd watchdog every {} ms", interval.as_millis() / 2);
+        tokio::spawn(feed_watchdog(interval / 2));
+    }
+}
+
+async fn feed_watchdog(interval: Duration) {
+    let mut timer = tokio::time::interval(interval);
+    loop {
+        timer.tick().await;
+        // a stuck main loop gets restarted by systemd
+        match health().stalled() {
+            None => {
+                if let Err(e) = sd_notify::notify(&[NotifyState::Watchdog]) {
+                    warn!("sd_notify WATCHDOG failed: {e}");
+                }
+            }
+            Some(silent) => error!("Main loop stalled for {silent} s, not feeding the watchdog"),
+        }
+    }
+}
+
+pub async fn get_health() -> (StatusCode, String) {
+    match health().stalled() {
+        None => (StatusCode::OK, "ok\n".to_string()),
+        Some(silent) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("main loop stalled for {silent} s\n"),
+        ),
+    }
+}
+
+async fn get_ready(pool: AnyPool) -> (StatusCode, String) {
+    if !health().is_ready() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "starting up\n".to_string());
+    }
+    match db_ping(&pool).await {
+        Ok(()) => (StatusCode::OK, "ready\n".to_string()),
+        Err(e) => (StatusCode::SERVICE_UNAVAILABLE, format!("database: {e}\n")),
+    }
+}
+
+// Waits for database changes, checking the database and the listener and showing signs of life meanwhile
+pub async fn wait_for_change(listener: &mut DbListener, db: &DbCtx) -> Result<DbChange, sqlx::Error> {
+    loop {
+        tokio::select! {
+            res = listener.recv() => return res,
+            _ = sleep(Duration::new(HEALTH_BEAT_INTERVAL, 0)) => {
+                if let Err(e) = db_ping(&db.dbc).await {
+                    error!("Database check failed: {e}");
+                } else if listener_alive(listener.probe(db), Duration::new(LISTENER_PROBE_TIMEOUT, 0)).await {
+                    health().beat();
+                }
+            }
+        }
+    }
+}
+
+async fn listener_alive(probe: impl Future<Output = Result<(), sqlx::Error>>, wait: Duration) -> bool {
+    match tokio::time::timeout(wait, probe).await {
+        Ok(Ok(())) => true,
+        Ok(Err(e)) => {
+            error!("Database listener check failed: {e}");
+            false
+        }
+        Err(_) => {
+            error!("Database listener did not answer within {wait:?}");
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stalls_after_the_timeout() {
+        let h = Health {
+            ready: AtomicBool::new(false),
+            last_beat: AtomicI64::new(Utc::now().timestamp() - 100),
+            stall_timeout: AtomicI64::new(60),
+        };
+        assert!(h.stalled().is_some_and(|s| s >= 100));
+        h.beat();
+        assert_eq!(h.stalled(), None);
+    }
+
+    #[tokio::test]
+    async fn stalled_listener_stops_the_beat() {
+        let wait = Duration::from_millis(50);
+        assert!(listener_alive(async { Ok(()) }, wait).await);
+        assert!(!listener_alive(async { Err(sqlx::Error::PoolClosed) }, wait).await);
+        assert!(!listener_alive(std::future::pending(), wait).await);
+    }
+}
+
+// EOF
@@ -1,5 +1,7 @@
 // str_util.rs
 
+use sha2::{Digest, Sha256};
+
 use crate::*;
 
 const TS_FMT_LONG: &str = "%Y-%m-%d %H:%M:%S";
@@ -98,5 +100,54 @@ impl CollapseWhiteSpace for &str {
         self.split_whitespace().collect::<Vec<&str>>().join(" ")
     }
 }
+pub trait SafeFileName {
+    fn safe_filename(self) -> String;
+}
+impl SafeFileName for &str {
+    // The readable part loses the sigil and any odd characters, e.g. "#a.b" and "&a_b" both give "a_b".
+    // A hash of the whole name keeps such names apart.
+    fn safe_filename(self) -> String {
+        // channel names usually start with a sigil that we do not want in file names
+        let name = self
+            .trim_start_matches(['#', '&', '!', '+'])
+            .chars()
+            .map(|c| {
+                if c.is_alphanumeric() || c == '-' || c == '_' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect::<String>();
+        let hash = Sha256::digest(self.as_bytes())
+            .iter()
+            .take(4)
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>();
+        match name.is_empty() {
+            true => format!("_-{hash}"),
+            false => format!("{name}-{hash}"),
+        }
+    }
+}
+
+pub trait TruncateChars {
+    fn truncate_chars(self, max_chars: usize, end: &str) -> String;
+}
+impl TruncateChars for &str {
+    fn truncate_chars(self, max_chars: usize, end: &str) -> String {
+        let Some((mut cut, _)) = self.char_indices().nth(max_chars) else {
+            return self.to_string();
+        };
+        // do not leave a partial html entity like "&am" behind
+        if let Some(amp) = self[..cut].rfind('&')
+            && !self[amp..cut].contains(';')
+            && self[amp..].find(';').is_some_and(|semi| semi <= 10)
+        {
+            cut = amp;
+        }
+        format!("{}{end}", &self[..cut])
+    }
+}
 
 // EOF
@@ -0,0 +1,140 @@
+// tera_util.rs
+
+use tera::{Kwargs, State, Tera, Value};
+use url::Url;
+
+use crate::*;
+
+const TS_FMT_DEFAULT: &str = "%Y-%m-%d %H:%M:%S";
+const TRUNCATE_END: &str = "...";
+const FAVICON_PATH: &str = "favicon.ico";
+const COLOUR_SATURATION: u32 = 55;
+const COLOUR_LIGHTNESS: u32 = 45;
+
+// Filters and functions available to all our Tera templates.
+// Register them before loading templates, since unknown filters are rejected at parse time.
+pub fn register_tera_extras(tera: &mut Tera) {
+    tera.register_filter("ts_format", ts_format);
+    tera.register_filter("ts_relative", ts_relative);
+    tera.register_filter("domain", domain);
+    tera.register_filter("favicon_url", favicon_url);
+    tera.register_filter("truncate_chars", truncate_chars);
+    tera.register_filter("channel_colour", channel_colour);
+    tera.register_function("now_ts", now_ts);
+}
+
+// Timestamps may come in as numbers or as the numeric strings of our context arrays
+fn ts_arg(value: &Value) -> tera::TeraResult<i64> {
+    value
+        .as_i64()
+        .or_else(|| value.as_str().and_then(|s| s.trim().parse::<i64>().ok()))
+        .ok_or_else(|| tera::Error::message(format!("not a timestamp: {value}")))
+}
+
+fn tz_arg(kwargs: &Kwargs) -> tera::TeraResult<Tz> {
+    match kwargs.get::<&str>("tz")? {
+        None => Ok(Tz::UTC),
+        Some(tz) => tz
+            .parse::<Tz>()
+            .map_err(|e| tera::Error::message(format!("invalid timezone \"{tz}\": {e}"))),
+    }
+}
+
+// chrono panics on an invalid specifier while formatting, so check it first
+fn fmt_arg(kwargs: &Kwargs) -> tera::TeraResult<&str> {
+    let fmt = kwargs.get::<&str>("fmt")?.unwrap_or(TS_FMT_DEFAULT);
+    match format::StrftimeItems::new(fmt).any(|item| item == format::Item::Error) {
+        true => Err(tera::Error::message(format!("invalid time format \"{fmt}\""))),
+        false => Ok(fmt),
+    }
+}
+
+// {{ ts | ts_format(fmt="%b %d %H:%M", tz="EET") }}
+fn ts_format(value: &Value, kwargs: Kwargs, _: &State) -> tera::TeraResult<String> {
+    Ok(ts_fmt(fmt_arg(&kwargs)?, ts_arg(value)?, &tz_arg(&kwargs)?))
+}
+
+// {{ ts | ts_relative }} gives e.g. "3 hours ago", an optional `now` overrides the current time
+fn ts_relative(value: &Value, kwargs: Kwargs, _: &State) -> tera::TeraResult<String> {
+    let now = match kwargs.get::<&Value>("now")? {
+        Some(now) => ts_arg(now)?,
+        None => Utc::now().timestamp(),
+    };
+    Ok(relative_time(ts_arg(value)?, now))
+}
+
+pub fn relative_time(ts: i64, now: i64) -> String {
+    let delta = now - ts;
+    let secs = delta.unsigned_abs();
+    if secs < 60 {
+        return "just now".to_string();
+    }
+
+    let (n, unit) = match secs {
+        s if s < 3600 => (s / 60, "minute"),
+        s if s < 86400 => (s / 3600, "hour"),
+        s if s < 30 * 86400 => (s / 86400, "day"),
+        s if s < 365 * 86400 => (s / (30 * 86400), "month"),
+        s => (s / (365 * 86400), "year"),
+    };
+    let plural = if n == 1 { "" } else { "s" };
+    if delta > 0 {
+        format!("{n} {unit}{plural} ago")
+    } else {
+        format!("in {n} {unit}{plural}")
+    }
+}
+
+fn url_host(url_s: &str) -> Option<String> {
+    let url = Url::parse(url_s).ok()?;
+    url.host_str().map(|h| h.to_string())
+}
+
+// {{ url | domain }} gives the host name without a leading "www."
+fn domain(value: &str, _: Kwargs, _: &State) -> String {
+    url_host(value)
+        .map(|h| h.strip_prefix("www.").map(|h| h.to_string()).unwrap_or(h))
+        .unwrap_or_default()
+}
+
+// {{ url | favicon_url }} gives the conventional favicon location of the site
+fn favicon_url(value: &str, _: Kwargs, _: &State) -> String {
+    match Url::parse(value) {
+        Ok(url) if url.host_str().is_some() => url
+            .join(&format!("/{FAVICON_PATH}"))
+            .map_or_else(|_| String::new(), |u| u.to_string()),
+        _ => String::new(),
+    }
+}
+
+// {{ title | truncate_chars(length=80) }}
+// Our strings are already html escaped, so never cut in the middle of an entity.
+fn truncate_chars(value: &str, kwargs: Kwargs, _: &State) -> tera::TeraResult<String> {
+    let length = kwargs.must_get::<usize>("length")?;
+    let end = kwargs.get::<&str>("end")?.unwrap_or(TRUNCATE_END);
+    Ok(value.truncate_chars(length, end))
+}
+
+// {{ channel | channel_colour }} gives a stable css colour for the channel name
+fn channel_colour(value: &str, kwargs: Kwargs, _: &State) -> tera::TeraResult<String> {
+    let saturation = kwargs.get::<u32>("saturation")?.unwrap_or(COLOUR_SATURATION);
+    let lightness = kwargs.get::<u32>("lightness")?.unwrap_or(COLOUR_LIGHTNESS);
+    Ok(format!(
+        "hsl({}, {saturation}%, {lightness}%)",
+        fnv1a(value.to_lowercase().as_bytes()) % 360
+    ))
+}
+
+// Unlike std hashers this is guaranteed to stay the same across releases
+fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes
+        .iter()
+        .fold(0x811c_9dc5_u32, |h, b| (h ^ *b as u32).wrapping_mul(0x0100_0193))
+}
+
+// {{ now_ts() }} gives the current unix timestamp, e.g. for ts_format and ts_relative
+fn now_ts(_: Kwargs, _: &State) -> i64 {
+    Utc::now().timestamp()
+}
+
+// EOF
@@ -0,0 +1,234 @@
+// url_util.rs
+
+use crate::*;
+
+pub const DEFAULT_URL_SCHEMES: [&str; 4] = ["http", "https", "ftp", "gemini"];
+// Match example:
+// "https://"
+const RE_SCHEME: &str = r"(?i)\b([a-z][a-z0-9+.-]*)://";
+// Match example:
+// "www.example.com"
+const RE_BARE_HOST: &str = r"\b(?:[\p{L}\p{N}](?:[\p{L}\p{N}-]*[\p{L}\p{N}])?\.)+\p{L}[\p{L}\p{N}-]*\b";
+// schemeless urls are stored with this
+const BARE_SCHEME: &str = "http://";
+// sentence punctuation and markdown emphasis, never the last character of a url
+const TRAILING_PUNCT: &[char] = &['.', ',', ':', ';', '!', '?', '\'', '*'];
+const BRACKETS: [(char, char); 3] = [('(', ')'), ('[', ']'), ('{', '}')];
+
+// Finds the urls in a message, see url_schemes and url_bare_domains in the config
+#[derive(Debug)]
+pub struct UrlExtractor {
+    // regex_url from older configs, capture group 1 is the url
+    legacy: Option<Regex>,
+    schemes: Vec<String>,
+    bare_domains: bool,
+    re_scheme: Regex,
+    re_bare_host: Regex,
+}
+
+impl UrlExtractor {
+    pub fn new<S: AsRef<str>>(schemes: &[S], bare_domains: bool) -> anyhow::Result<Self> {
+        Ok(Self {
+            legacy: None,
+            schemes: schemes.iter().map(|s| s.as_ref().to_ascii_lowercase()).collect(),
+            bare_domains,
+            re_scheme: Regex::new(RE_SCHEME)?,
+            re_bare_host: Regex::new(RE_BARE_HOST)?,
+        })
+    }
+
+    pub fn with_regex(re_url: &str) -> anyhow::Result<Self> {
+        let mut extractor = Self::new(&DEFAULT_URL_SCHEMES, false)?;
+        extractor.legacy = Some(Regex::new(re_url)?);
+        Ok(extractor)
+    }
+
+    // The urls in the order they appear in the message
+    pub fn find(&self, msg: &str) -> Vec<String> {
+        if let Some(re) = &self.legacy {
+            return re.captures_iter(msg).map(|c| c[1].to_string()).collect();
+        }
+
+        let mut found: Vec<(usize, usize, String)> = Vec::new();
+        let mut pos = 0;
+        for m in self.re_scheme.captures_iter(msg) {
+            let (Some(whole), Some(scheme)) = (m.get(0), m.get(1)) else {
+                continue;
+            };
+            // e.g. a redirect parameter inside the previous url
+            if whole.start() < pos || !self.schemes.contains(&scheme.as_str().to_ascii_lowercase()) {
+                continue;
+            }
+            let start = whole.start();
+            let url = trim_url(&msg[start..url_end(msg, whole.end())]);
+            if url.len() <= whole.len() {
+                continue;
+            }
+            pos = start + url.len();
+            found.push((start, pos, url.to_string()));
+        }
+
+        if self.bare_domains {
+            let n_scheme = found.len();
+            for m in self.re_bare_host.find_iter(msg) {
+                if found[..n_scheme].iter().any(|(s, e, _)| m.start() < *e && m.end() > *s) {
+                    continue;
+                }
+                // email addresses, file paths and the like
+                if msg[..m.start()].ends_with(['@', '.', '/', '\\', ':', '-']) || msg[m.end()..].starts_with('@') {
+                    continue;
+                }
+                if !known_domain(&m.as_str().to_lowercase()) {
+                    continue;
+                }
+                let rest = &msg[m.end()..];
+                let end = if rest.starts_with('/')
+                    || (rest.starts_with(':') && rest[1..].starts_with(|c: char| c.is_ascii_digit()))
+                {
+                    url_end(msg, m.end())
+                } else {
+                    m.end()
+                };
+                let url = trim_url(&msg[m.start()..end]);
+                found.push((m.start(), m.start() + url.len(), format!("{BARE_SCHEME}{url}")));
+            }
+            found.sort_by_key(|(start, _, _)| *start);
+        }
+        found.into_iter().map(|(_, _, url)| url).collect()
+    }
+}
+
+// Where the url starting before `from` ends. Whitespace, control characters (irssi formatting),
+// quotes, angle brackets and closing brackets without a matching opening one inside the url end it.
+fn url_end(msg: &str, from: usize) -> usize {
+    let mut depth = [0usize; BRACKETS.len()];
+    for (i, c) in msg[from..].char_indices() {
+        if c.is_whitespace() || c.is_control() || matches!(c, '<' | '>' | '"' | '`') {
+            return from + i;
+        }
+        if let Some(b) = BRACKETS.iter().position(|(open, _)| *open == c) {
+            depth[b] += 1;
+        } else if let Some(b) = BRACKETS.iter().position(|(_, close)| *close == c) {
+            if depth[b] == 0 {
+                return from + i;
+            }
+            depth[b] -= 1;
+        }
+    }
+    msg.len()
+}
+
+fn trim_url(url: &str) -> &str {
+    url.trim_end_matches(TRAILING_PUNCT)
+}
+
+// A registrable name under a suffix on the public suffix list, "main.rs" still passes as .rs is Serbia
+fn known_domain(host: &str) -> bool {
+    psl::suffix(host.as_bytes()).is_some_and(|s| s.is_known()) && psl::domain(host.as_bytes()).is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (message, urls found) with bare domains enabled
+    const CORPUS: &[(&str, &[&str])] = &[
+        ("https://example.com", &["https://example.com"]),
+        ("see https://example.com.", &["https://example.com"]),
+        ("see https://example.com/foo, and more", &["https://example.com/foo"]),
+        ("what? https://example.com/?q=1&x=2!", &["https://example.com/?q=1&x=2"]),
+        ("HTTPS://EXAMPLE.COM/A", &["HTTPS://EXAMPLE.COM/A"]),
+        ("(https://example.com/foo)", &["https://example.com/foo"]),
+        ("(see https://example.com/foo).", &["https://example.com/foo"]),
+        (
+            "https://en.wikipedia.org/wiki/Rust_(programming_language)",
+            &["https://en.wikipedia.org/wiki/Rust_(programming_language)"],
+        ),
+        (
+            "(https://en.wikipedia.org/wiki/Rust_(programming_language))",
+            &["https://en.wikipedia.org/wiki/Rust_(programming_language)"],
+        ),
+        ("[https://example.com]", &["https://example.com"]),
+        ("{https://example.com/{id}}", &["https://example.com/{id}"]),
+        ("<https://example.com/a>", &["https://example.com/a"]),
+        ("\"https://example.com/a\"", &["https://example.com/a"]),
+        ("'https://example.com/a'", &["https://example.com/a"]),
+        ("`https://example.com/a`", &["https://example.com/a"]),
+        ("**https://example.com/a**", &["https://example.com/a"]),
+        ("[docs](https://example.com/docs)", &["https://example.com/docs"]),
+        (
+            "[https://a.example.com](https://b.example.com)",
+            &["https://a.example.com", "https://b.example.com"],
+        ),
+        ("http://[::1]:8080/status", &["http://[::1]:8080/status"]),
+        ("https://example.com/a'b", &["https://example.com/a'b"]),
+        (
+            "two https://a.example.com and https://b.example.com/x",
+            &["https://a.example.com", "https://b.example.com/x"],
+        ),
+        (
+            "https://example.com/?next=https://other.example.com",
+            &["https://example.com/?next=https://other.example.com"],
+        ),
+        ("\x02https://example.com\x02 bold", &["https://example.com"]),
+        ("\x1fhttps://example.com\x1f", &["https://example.com"]),
+        (
+            "ftp://ftp.example.com/pub/file.tgz",
+            &["ftp://ftp.example.com/pub/file.tgz"],
+        ),
+        ("gemini://gemini.example.com/", &["gemini://gemini.example.com/"]),
+        ("irc://irc.example.com/#chan", &[]),
+        ("javascript://alert(1)", &[]),
+        ("xhttps://example.com", &[]),
+        ("just http:// nothing", &[]),
+        ("http://.", &[]),
+        ("https://example.com/ä/ö", &["https://example.com/ä/ö"]),
+        (
+            "https://esimerkki.fi/kädenvääntö",
+            &["https://esimerkki.fi/kädenvääntö"],
+        ),
+        // bare domains
+        ("www.example.com", &["http://www.example.com"]),
+        ("go to example.com/path.", &["http://example.com/path"]),
+        ("example.co.uk:8080/x y", &["http://example.co.uk:8080/x"]),
+        ("(example.com)", &["http://example.com"]),
+        ("mail me at user@example.com", &[]),
+        ("e.g. this, i.e. that", &[]),
+        ("node.js and file.txt", &[]),
+        ("/var/www.example.com/index", &[]),
+        ("version 1.2.3", &[]),
+        (
+            "https://example.com and www.example.org",
+            &["https://example.com", "http://www.example.org"],
+        ),
+        (
+            "www.example.com then https://example.net",
+            &["http://www.example.com", "https://example.net"],
+        ),
+    ];
+
+    #[test]
+    fn corpus() {
+        let extractor = UrlExtractor::new(&DEFAULT_URL_SCHEMES, true).unwrap();
+        for (msg, urls) in CORPUS {
+            assert_eq!(extractor.find(msg), *urls, "{msg:?}");
+        }
+    }
+
+    #[test]
+    fn bare_domains_are_optional() {
+        let extractor = UrlExtractor::new(&["https"], false).unwrap();
+        assert_eq!(
+            extractor.find("www.example.com and https://example.com http://example.net"),
+            ["https://example.com"]
+        );
+    }
+
+    #[test]
+    fn legacy_regex() {
+        let extractor = UrlExtractor::with_regex(r"(https?://\S+)").unwrap();
+        assert_eq!(extractor.find("(https://example.com)."), ["https://example.com)."]);
+    }
+}
+
+// EOF
@@ -4,11 +4,12 @@ use url::Url;
 
 use crate::*;
 
+// defaults for the [meta] timeouts, in seconds
 const CONN_TIMEOUT: u64 = 5;
 const REQW_TIMEOUT: u64 = 10;
 
-pub async fn get_text_body(url_s: &str) -> anyhow::Result<Option<(String, String)>> {
-    let (body, ct) = get_body(url_s).await?;
+pub async fn get_text_body(url_s: &str, mc: &MetaConfig) -> anyhow::Result<Option<(String, String)>> {
+    let (body, ct) = get_body(url_s, mc).await?;
 
     if ct.starts_with("text/") {
         Ok(Some((body, ct)))
@@ -18,13 +19,13 @@ pub async fn get_text_body(url_s: &str) -> anyhow::Result<Option<(String, String
     }
 }
 
-pub async fn get_body(url_s: &str) -> anyhow::Result<(String, String)> {
+pub async fn get_body(url_s: &str, mc: &MetaConfig) -> anyhow::Result<(String, String)> {
     // We want a normalized and valid url, IDN handled etc.
     let url = Url::parse(url_s)?;
 
     let c = reqwest::ClientBuilder::new()
-        .connect_timeout(Duration::from_secs(CONN_TIMEOUT))
-        .timeout(Duration::from_secs(REQW_TIMEOUT))
+        .connect_timeout(Duration::from_secs(mc.connect_timeout.unwrap_or(CONN_TIMEOUT)))
+        .timeout(Duration::from_secs(mc.request_timeout.unwrap_or(REQW_TIMEOUT)))
         .user_agent(format!(
             "Rust/hyper/{} v{}",
             env!("CARGO_PKG_NAME"),
@@ -34,7 +35,19 @@ pub async fn get_body(url_s: &str) -> anyhow::Result<(String, String)> {
         .danger_accept_invalid_certs(true)
         .build()?;
 
-    let resp = c.get(url).send().await?.error_for_status()?;
+    let resp = match c.get(url).send().await.and_then(|r| r.error_for_status()) {
+        Ok(resp) => resp,
+        Err(e) => {
+            let status = match e.status() {
+                Some(s) => s.as_str().to_string(),
+                None if e.is_timeout() => "timeout".to_string(),
+                None => "error".to_string(),
+            };
+            metrics().meta_fetch.with_label_values(&[status]).inc();
+            return Err(e.into());
+        }
+    };
+    metrics().meta_fetch.with_label_values(&[resp.status().as_str()]).inc();
     let ct = String::from_utf8_lossy(
         resp.headers()
             .get(reqwest::header::CONTENT_TYPE)
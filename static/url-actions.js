@@ -9,6 +9,8 @@ function search_url(form) {
         nick: form.nick.value,
         url: form.url.value,
         title: form.title.value,
+        msg: form.msg.value,
+        kind: form.kind.value,
     };
     const url = form.dataset.searchUrl + "?" + new URLSearchParams(params).toString();
     console.log("URL: " + url);